@@ -0,0 +1,151 @@
+use crate::JsonHelper;
+use axum::http::{header, HeaderMap, StatusCode};
+use serde_json::Value;
+use std::net::IpAddr;
+
+//Config sections which are not catalogs and must never be served
+const RESERVED_SECTIONS: [&str; 3] = ["server", "client", "xcopy"];
+
+//Check whether addr with request headers may read catalog, return the catalog config on success.
+//Catalog config keys: "tokens": ["secret",...] bearer tokens, "allow_ips": ["192.168.0.0/16","::1",...]
+//A catalog must be a config section with a "path", server config key "strict_catalogs": false
+//also serves sections without one from ./download
+pub(crate) fn check_catalog_access<'a>(
+    config: &'a Value,
+    catalog: &str,
+    addr: IpAddr,
+    headers: &HeaderMap,
+) -> Result<&'a Value, (StatusCode, String)> {
+    let strict = config["server"]["strict_catalogs"].bool(true);
+    let catalog_config = &config[catalog];
+    let is_catalog = !catalog.is_empty()
+        && !RESERVED_SECTIONS.contains(&catalog)
+        && catalog_config.is_object()
+        && (!strict || catalog_config["path"].is_string());
+    if !is_catalog {
+        return Err((StatusCode::NOT_FOUND, format!("catalog {} does not exist", catalog)));
+    }
+
+    if let Some(allow_ips) = catalog_config["allow_ips"].as_array() {
+        let allowed = allow_ips
+            .iter()
+            .filter_map(|x| x.as_str())
+            .any(|cidr| ip_in_cidr(addr, cidr));
+        if !allowed {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("{} is not allowed to access catalog {}", addr, catalog),
+            ));
+        }
+    }
+
+    if let Some(tokens) = catalog_config["tokens"].as_array() {
+        if !tokens.is_empty() {
+            let token = headers
+                .get(header::AUTHORIZATION)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.strip_prefix("Bearer "))
+                .unwrap_or("");
+            let authorized = tokens
+                .iter()
+                .filter_map(|x| x.as_str())
+                .any(|x| constant_time_eq(x.as_bytes(), token.as_bytes()));
+            if !authorized {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    format!("catalog {} requires a valid bearer token", catalog),
+                ));
+            }
+        }
+    }
+    Ok(catalog_config)
+}
+
+//"10.0.0.0/8", "192.168.1.10", "fe80::/10"
+fn ip_in_cidr(addr: IpAddr, cidr: &str) -> bool {
+    let (network, prefix) = match cidr.trim().split_once('/') {
+        Some((network, prefix)) => (network, prefix.parse::<u32>().ok()),
+        None => (cidr.trim(), None),
+    };
+    let network = match network.parse::<IpAddr>() {
+        Ok(network) => network,
+        Err(_) => return false,
+    };
+    let addr = match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        _ => addr,
+    };
+    match (addr, network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => {
+            let prefix = prefix.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(network)) => {
+            let prefix = prefix.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use serde_json::json;
+
+    #[test]
+    fn test_ip_in_cidr() {
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        assert!(ip_in_cidr(ip("192.168.1.10"), "192.168.0.0/16"));
+        assert!(ip_in_cidr(ip("192.168.1.10"), "192.168.1.10"));
+        assert!(!ip_in_cidr(ip("192.168.1.11"), "192.168.1.10"));
+        assert!(!ip_in_cidr(ip("10.0.0.1"), "192.168.0.0/16"));
+        assert!(ip_in_cidr(ip("10.0.0.1"), "0.0.0.0/0"));
+        assert!(ip_in_cidr(ip("::ffff:10.0.0.1"), "10.0.0.0/8"));
+        assert!(ip_in_cidr(ip("fe80::1"), "fe80::/10"));
+        assert!(!ip_in_cidr(ip("::1"), "127.0.0.1"));
+        assert!(!ip_in_cidr(ip("10.0.0.1"), "not an ip"));
+    }
+
+    #[test]
+    fn test_check_catalog_access() {
+        let config = json!({
+            "server": {"strict_catalogs": true},
+            "client": {"path": "./demo_recv"},
+            "open": {"path": "./open"},
+            "locked": {"path": "./locked", "tokens": ["s3cret"], "allow_ips": ["10.0.0.0/8"]}
+        });
+        let lan = "10.1.2.3".parse::<IpAddr>().unwrap();
+        let wan = "8.8.8.8".parse::<IpAddr>().unwrap();
+        let no_auth = HeaderMap::new();
+        let mut auth = HeaderMap::new();
+        auth.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer s3cret"));
+        let mut bad_auth = HeaderMap::new();
+        bad_auth.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer guess"));
+        let status = |catalog, addr, headers| {
+            check_catalog_access(&config, catalog, addr, headers).map(|_| ()).map_err(|x| x.0)
+        };
+
+        assert_eq!(status("open", wan, &no_auth), Ok(()));
+        assert_eq!(status("missing", wan, &no_auth), Err(StatusCode::NOT_FOUND));
+        assert_eq!(status("client", wan, &no_auth), Err(StatusCode::NOT_FOUND));
+        assert_eq!(status("", wan, &no_auth), Err(StatusCode::NOT_FOUND));
+        assert_eq!(status("locked", lan, &auth), Ok(()));
+        assert_eq!(status("locked", wan, &auth), Err(StatusCode::FORBIDDEN));
+        assert_eq!(status("locked", lan, &no_auth), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(status("locked", lan, &bad_auth), Err(StatusCode::UNAUTHORIZED));
+
+        let config = json!({"other": {}});
+        assert!(check_catalog_access(&config, "other", wan, &no_auth).is_err());
+        let config = json!({"server": {"strict_catalogs": false}, "other": {}});
+        assert!(check_catalog_access(&config, "other", wan, &no_auth).is_ok());
+        assert!(check_catalog_access(&config, "missing", wan, &no_auth).is_err());
+    }
+}
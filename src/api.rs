@@ -1,35 +1,115 @@
+use crate::access::check_catalog_access;
 use crate::base16::{base16_decode, base16_encode};
-use crate::fileutil::{get_full_of_file, get_part_of_file};
+use crate::compression::{choose_encoding, encode, is_compressible, MIN_COMPRESS_SIZE};
+use crate::fileutil::open_part_of_file;
+use crate::manifest::{load_manifest, MANIFEST_NAME};
+use crate::rate_limit::{RateLimiter, Schedule};
 use crate::AppContext;
 use crate::JsonHelper;
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Result};
 use axum::{
+    body::StreamBody,
     extract::{ConnectInfo, Extension, Path},
     http::{
-        header::{HeaderMap, HeaderName, HeaderValue},
+        header::{self, HeaderMap, HeaderName, HeaderValue},
         StatusCode,
     },
+    response::{IntoResponse, Response},
     routing::get,
     Router,
 };
+use futures_util::{Stream, StreamExt};
+use once_cell::sync::Lazy;
 use serde_json::Value;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio_util::io::ReaderStream;
+
+//Read buffer size of the streamed response body
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
 
 pub(crate) fn api(context: Arc<AppContext>) -> Router {
+    let rate_limits = RateLimits::from_config(&context.config["server"]).unwrap_or_else(|e| {
+        println!("Ignore server rate limits: {}", e);
+        RateLimits::default()
+    });
     Router::new()
         .route("/download/:download", get(download_file))
+        .route("/files/:catalog/*file", get(get_file))
         .layer(Extension(context))
+        .layer(Extension(Arc::new(rate_limits)))
+}
+
+//Server config "rate_limit" caps all downloads together, "connection_rate_limit" each response
+//stream on its own, "client_rate_limit" each client ip whose parallel connections share the bucket
+#[derive(Default)]
+pub(crate) struct RateLimits {
+    all: Option<Arc<RateLimiter>>,
+    connection: Option<Schedule>,
+    client: Option<Schedule>,
+    clients: Mutex<HashMap<IpAddr, Arc<RateLimiter>>>,
+}
+
+impl RateLimits {
+    fn from_config(server_config: &Value) -> Result<Self> {
+        Ok(RateLimits {
+            all: RateLimiter::from_config(&server_config["rate_limit"])?.map(Arc::new),
+            connection: Schedule::from_config(&server_config["connection_rate_limit"])?,
+            client: Schedule::from_config(&server_config["client_rate_limit"])?,
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    //The buckets of one response stream, a new connection bucket each time
+    fn for_connection(&self, ip: IpAddr) -> Vec<Arc<RateLimiter>> {
+        let mut limiters: Vec<Arc<RateLimiter>> = self.all.iter().cloned().collect();
+        if let Some(schedule) = &self.connection {
+            limiters.push(Arc::new(RateLimiter::new(schedule.clone())));
+        }
+        if let (Some(schedule), Ok(mut clients)) = (&self.client, self.clients.lock()) {
+            //forget idle clients once there are many
+            if clients.len() > 1024 {
+                clients.retain(|_, x| Arc::strong_count(x) > 1);
+            }
+            let limiter = clients
+                .entry(ip)
+                .or_insert_with(|| Arc::new(RateLimiter::new(schedule.clone())));
+            limiters.push(limiter.clone());
+        }
+        limiters
+    }
+}
+
+//Hold back every piece of the body until the limiters allow it
+fn throttle<S, B>(stream: S, limiters: Vec<Arc<RateLimiter>>) -> impl Stream<Item = S::Item>
+where
+    S: Stream<Item = std::io::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    stream.then(move |item| {
+        let limiters = limiters.clone();
+        async move {
+            if let Ok(bytes) = &item {
+                for limiter in &limiters {
+                    limiter.acquire(bytes.as_ref().len() as u64).await;
+                }
+            }
+            item
+        }
+    })
 }
 async fn download_file(
     Extension(context): Extension<Arc<AppContext>>,
+    Extension(rate_limits): Extension<Arc<RateLimits>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Path(params): Path<String>,
-) -> (StatusCode, HeaderMap, Vec<u8>) {
-    use std::path::Path;
+    request_headers: HeaderMap,
+) -> Response {
     use tracing::info;
-    fn response_error(msg: &str) -> (StatusCode, HeaderMap, Vec<u8>) {
+    fn response_error(status: StatusCode, msg: &str) -> Response {
         let mut headers = HeaderMap::new();
         headers.insert(
             HeaderName::from_static("x-body-is-error"),
@@ -39,38 +119,47 @@ async fn download_file(
             HeaderName::from_static("content-type"),
             HeaderValue::from_static("text/plain;charset=utf-8"),
         ); //.header("content-type", "text/plain;charset=utf-8")
-        (StatusCode::NOT_ACCEPTABLE, headers, Vec::<u8>::from(msg))
+        (status, headers, Vec::<u8>::from(msg)).into_response()
     }
     //debug!("params={} from {}", params, addr);
     if let Ok(params) = base16_decode(&params) {
         let config = &context.config.clone();
-        let params: Value = serde_json::from_str(&params).unwrap();
-        let catalog = params["catalog"].str("tcsoftV6");
-        let path = config[catalog]["path"].str("download");
+        let params: Value = match serde_json::from_str(&params) {
+            Ok(params) => params,
+            Err(e) => {
+                info!("from {:?}, reject params {}: {}", addr, params, e);
+                return response_error(StatusCode::BAD_REQUEST, &format!("Error：invalid params {}: {}", params, e));
+            }
+        };
+        let catalog = params["catalog"].str("");
+        let catalog_config = match check_catalog_access(config, catalog, addr.ip(), &request_headers) {
+            Ok(catalog_config) => catalog_config,
+            Err((status, msg)) => {
+                info!("from {:?}, reject catalog {}: {}", addr, catalog, msg);
+                return response_error(status, &format!("Error：{}", msg));
+            }
+        };
+        let path = catalog_config["path"].str("download");
         let file = params["file"].str("");
         let skip = params["skip"].u64(0);
         let take = params["take"].u64(0);
 
-        let bytes = if !file.is_empty() {
-            let file_name = String::from(path) + "/" + file;
-            if file == "filelist.txt" {
+        let reader = if !file.is_empty() {
+            if file == "filelist.txt" || file == MANIFEST_NAME {
                 info!("from {:?}, download {}", addr, catalog);
             }
-            let path = Path::new(&file_name);
-            if path.exists() {
-                if take == 0 {
-                    get_full_of_file(&file_name).await
-                } else {
-                    get_part_of_file(&file_name, skip, take).await
+            match resolve_catalog_file(path, file).await {
+                Ok(file_name) => open_part_of_file(&file_name, skip, take).await,
+                Err((status, msg)) => {
+                    info!("from {:?}, reject {}: {}", addr, file, msg);
+                    return response_error(status, &format!("Error：{}", msg));
                 }
-            } else {
-                Err(anyhow!("{} does not exist", file_name))
             }
         } else {
             Err(anyhow!("file name not provided"))
         };
-        match bytes {
-            Ok((skip, take, bytes)) => {
+        match reader {
+            Ok((skip, take, reader)) => {
                 let mut headers = HeaderMap::new();
                 headers.insert(HeaderName::from_static("x-skip"), HeaderValue::from(skip)); // .header("x-skip", skip)
                 headers.insert(HeaderName::from_static("x-take"), HeaderValue::from(take)); // .header("x-take", take)
@@ -78,15 +167,445 @@ async fn download_file(
                     HeaderName::from_static("content-type"),
                     HeaderValue::from_static("application/octet-stream"),
                 ); //.header("content-type", "application/octet-stream")
-                (StatusCode::OK, headers, bytes)
+                //x-take stays the raw size, the compressed length is unknown until sent
+                let accept_encoding = request_headers
+                    .get(header::ACCEPT_ENCODING)
+                    .and_then(|x| x.to_str().ok())
+                    .unwrap_or("");
+                let encoding = choose_encoding(accept_encoding)
+                    .filter(|_| take >= MIN_COMPRESS_SIZE && is_compressible(catalog_config, file));
+                let reader = match encoding {
+                    Some(encoding) => {
+                        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+                        encode(reader, encoding)
+                    }
+                    None => {
+                        headers.insert(
+                            HeaderName::from_static("content-length"),
+                            HeaderValue::from(take),
+                        );
+                        Box::new(reader)
+                    }
+                };
+                let stream = ReaderStream::with_capacity(reader, STREAM_BUFFER_SIZE);
+                let body = StreamBody::new(throttle(stream, rate_limits.for_connection(addr.ip())));
+                (StatusCode::OK, headers, body).into_response()
             }
-            Err(e) => response_error(&format!("Error：{:?}", e)),
+            Err(e) => response_error(StatusCode::NOT_ACCEPTABLE, &format!("Error：{:?}", e)),
         }
     } else {
-        response_error(&format!(
+        response_error(StatusCode::NOT_ACCEPTABLE, &format!(
             "Error：download file fail, expect base16 encoded string as param,for example: {}, but get param: {} ",
             base16_encode(r#"{"catalog":"tcsoftV6","file":"filelist.txt"}"#).unwrap(),
             params
         ))
     }
 }
+
+//Resolve a catalog-relative file name to a path inside the catalog root,
+//absolute paths, ".." and symlinks leading outside of the root are refused with 403
+async fn resolve_catalog_file(
+    root: &str,
+    file: &str,
+) -> std::result::Result<String, (StatusCode, String)> {
+    use std::path::{Component, Path};
+    let forbidden = || (StatusCode::FORBIDDEN, format!("{} is outside of the catalog", file));
+    let relative = Path::new(file);
+    let is_relative = relative
+        .components()
+        .all(|x| matches!(x, Component::Normal(_) | Component::CurDir));
+    if file.is_empty() || !is_relative {
+        return Err(forbidden());
+    }
+    let not_found = || (StatusCode::NOT_FOUND, format!("{} does not exist", file));
+    let root = tokio::fs::canonicalize(root).await.map_err(|_| not_found())?;
+    let file_name = tokio::fs::canonicalize(root.join(relative))
+        .await
+        .map_err(|_| not_found())?;
+    if !file_name.starts_with(&root) {
+        return Err(forbidden());
+    }
+    file_name.to_str().map(String::from).ok_or_else(not_found)
+}
+
+//Digests of the files of each catalog with the mtime of the manifest they were read from
+type CachedDigests = (String, SystemTime, Arc<HashMap<String, (String, u64, Option<u64>)>>);
+static DIGEST_CACHE: Lazy<Mutex<HashMap<String, CachedDigests>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+//Look up the digest of file in the catalog manifest, used as ETag,
+//the manifest is only read again after it changed.
+//None unless the file on disk still has the size and mtime it was indexed with,
+//a file changed since must not be resumed with ranges of its old version
+async fn get_file_digest(
+    path: &str,
+    catalog: &str,
+    list_name: &str,
+    file: &str,
+    meta: &std::fs::Metadata,
+) -> Option<String> {
+    let mut manifest_name = String::from(path) + "/" + MANIFEST_NAME;
+    let mtime = match tokio::fs::metadata(&manifest_name).await {
+        Ok(meta) => meta.modified().ok()?,
+        Err(_) => {
+            manifest_name = String::from(path) + "/" + list_name;
+            tokio::fs::metadata(&manifest_name).await.ok()?.modified().ok()?
+        }
+    };
+    let cached = DIGEST_CACHE.lock().ok()?.get(catalog).and_then(|x| {
+        Some(x.2.clone()).filter(|_| x.0 == manifest_name && x.1 == mtime)
+    });
+    let digests = match cached {
+        Some(digests) => digests,
+        None => {
+            let manifest = load_manifest(path, catalog, list_name).await.ok()?;
+            let digests = Arc::new(manifest.digests());
+            DIGEST_CACHE
+                .lock()
+                .ok()?
+                .insert(catalog.to_string(), (manifest_name, mtime, digests.clone()));
+            digests
+        }
+    };
+    let (digest, size, mtime) = digests.get(file)?;
+    let modified = meta
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some(digest.clone()).filter(|_| *size == meta.len() && *mtime == Some(modified))
+}
+
+//Parse a single "bytes=" Range header into (skip,take),
+//Ok(None) means the header is ignored and the whole file is sent, Err means it is unsatisfiable
+fn parse_range(range: &str, file_size: u64) -> Result<Option<(u64, u64)>> {
+    let range = match range.trim().strip_prefix("bytes=") {
+        Some(range) if !range.contains(',') => range,
+        _ => return Ok(None),
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return Ok(None),
+    };
+    if start.is_empty() {
+        let suffix = match end.parse::<u64>() {
+            Ok(suffix) => suffix,
+            Err(_) => return Ok(None),
+        };
+        if suffix == 0 || file_size == 0 {
+            return Err(anyhow!("range {} not satisfiable", range));
+        }
+        let take = suffix.min(file_size);
+        return Ok(Some((file_size - take, take)));
+    }
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return Ok(None),
+    };
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return Ok(None),
+        }
+    };
+    if start >= file_size {
+        return Err(anyhow!("range {} not satisfiable", range));
+    }
+    let end = end.min(file_size - 1);
+    Ok(Some((start, end - start + 1)))
+}
+
+//Plain GET /api/files/{catalog}/{file} with Range, If-Range and ETag support
+async fn get_file(
+    Extension(context): Extension<Arc<AppContext>>,
+    Extension(rate_limits): Extension<Arc<RateLimits>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((catalog, file)): Path<(String, String)>,
+    request_headers: HeaderMap,
+) -> Response {
+    use tracing::info;
+    let catalog_config = match check_catalog_access(&context.config, &catalog, addr.ip(), &request_headers) {
+        Ok(catalog_config) => catalog_config,
+        Err((status, msg)) => {
+            info!("from {:?}, reject catalog {}: {}", addr, catalog, msg);
+            let mut headers = HeaderMap::new();
+            if status == StatusCode::UNAUTHORIZED {
+                headers.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            return (status, headers, msg).into_response();
+        }
+    };
+    let path = catalog_config["path"].str("download");
+    let list_name = catalog_config["list_name"].str("filelist.txt");
+    let file = file.trim_start_matches('/');
+    let file_name = match resolve_catalog_file(path, file).await {
+        Ok(file_name) => file_name,
+        Err((status, msg)) => {
+            info!("from {:?}, reject {}: {}", addr, file, msg);
+            return (status, msg).into_response();
+        }
+    };
+    let meta = match tokio::fs::metadata(&file_name).await {
+        Ok(meta) if meta.is_file() => meta,
+        _ => return (StatusCode::NOT_FOUND, format!("{} does not exist", file)).into_response(),
+    };
+    let file_size = meta.len();
+    if file == list_name || file == MANIFEST_NAME {
+        info!("from {:?}, download {}", addr, catalog);
+    }
+    let etag = get_file_digest(path, &catalog, list_name, file, &meta)
+        .await
+        .map(|digest| format!("\"{}\"", digest));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    if let Some(etag) = etag.as_ref().and_then(|x| HeaderValue::from_str(x).ok()) {
+        headers.insert(header::ETAG, etag);
+    }
+
+    //If-Range only allows the partial response while the client still holds the current version
+    let if_range_matched = match request_headers.get(header::IF_RANGE) {
+        Some(if_range) => etag.is_some() && if_range.to_str().ok() == etag.as_deref(),
+        None => true,
+    };
+    let range = match request_headers.get(header::RANGE) {
+        Some(range) if if_range_matched => parse_range(range.to_str().unwrap_or(""), file_size),
+        _ => Ok(None),
+    };
+    let (status, skip, take) = match range {
+        Ok(Some((skip, take))) => {
+            let content_range = format!("bytes {}-{}/{}", skip, skip + take - 1, file_size);
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).unwrap(),
+            );
+            (StatusCode::PARTIAL_CONTENT, skip, take)
+        }
+        Ok(None) => (StatusCode::OK, 0, file_size),
+        Err(_) => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", file_size)).unwrap(),
+            );
+            return (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response();
+        }
+    };
+    match open_part_of_file(&file_name, skip, take).await {
+        Ok((_, take, reader)) => {
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(take));
+            let stream = ReaderStream::with_capacity(reader, STREAM_BUFFER_SIZE);
+            let body = StreamBody::new(throttle(stream, rate_limits.for_connection(addr.ip())));
+            (status, headers, body).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error：{:?}", e)).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{download_file, get_file, get_file_digest, parse_range, resolve_catalog_file, RateLimits};
+    use crate::base16::base16_encode;
+    use crate::fileutil::TempPath;
+    use crate::AppContext;
+    use axum::body::HttpBody;
+    use axum::extract::{ConnectInfo, Extension, Path};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::Response;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    //the extractors of one request from localhost against config
+    fn request_parts(
+        config: Value,
+    ) -> (
+        Extension<Arc<AppContext>>,
+        Extension<Arc<RateLimits>>,
+        ConnectInfo<std::net::SocketAddr>,
+    ) {
+        let limits = RateLimits::from_config(&json!({})).unwrap();
+        (
+            Extension(Arc::new(AppContext { config })),
+            Extension(Arc::new(limits)),
+            ConnectInfo("127.0.0.1:9191".parse().unwrap()),
+        )
+    }
+
+    async fn response_text(response: Response) -> (StatusCode, String) {
+        let status = response.status();
+        let mut body = response.into_body();
+        let mut text = Vec::new();
+        while let Some(bytes) = body.data().await {
+            text.extend_from_slice(&bytes.unwrap());
+        }
+        (status, String::from_utf8(text).unwrap())
+    }
+
+    fn not_found(catalog: &str) -> String {
+        format!("catalog {} does not exist", catalog)
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let config = serde_json::json!({
+            "rate_limit": "50 MiB",
+            "connection_rate_limit": "2 MiB",
+            "client_rate_limit": "5 MiB"
+        });
+        let limits = RateLimits::from_config(&config).unwrap();
+        let ip = "10.0.0.1".parse().unwrap();
+        let first = limits.for_connection(ip);
+        let second = limits.for_connection(ip);
+        assert_eq!(first.len(), 3);
+        //the global and client buckets are shared, the connection bucket is not
+        assert!(Arc::ptr_eq(&first[0], &second[0]));
+        assert!(!Arc::ptr_eq(&first[1], &second[1]));
+        assert!(Arc::ptr_eq(&first[2], &second[2]));
+        assert!(!Arc::ptr_eq(&first[2], &limits.for_connection("10.0.0.2".parse().unwrap())[2]));
+        assert!(RateLimits::default().for_connection(ip).is_empty());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000).unwrap(), Some((0, 100)));
+        assert_eq!(parse_range("bytes=900-", 1000).unwrap(), Some((900, 100)));
+        assert_eq!(parse_range("bytes=900-5000", 1000).unwrap(), Some((900, 100)));
+        assert_eq!(parse_range("bytes=-100", 1000).unwrap(), Some((900, 100)));
+        assert_eq!(parse_range("bytes=-5000", 1000).unwrap(), Some((0, 1000)));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000).unwrap(), None);
+        assert_eq!(parse_range("bytes=9-1", 1000).unwrap(), None);
+        assert_eq!(parse_range("items=0-1", 1000).unwrap(), None);
+        assert!(parse_range("bytes=1000-", 1000).is_err());
+        assert!(parse_range("bytes=-0", 1000).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_catalog_file() {
+        let base = TempPath::new("resolve");
+        let base = base.path();
+        let root = base.join("catalog");
+        tokio::fs::create_dir_all(root.join("sub")).await.unwrap();
+        tokio::fs::write(root.join("sub/a.txt"), b"a").await.unwrap();
+        tokio::fs::write(base.join("secret.txt"), b"s").await.unwrap();
+        let root_str = root.to_str().unwrap();
+        let status = |result: Result<String, (StatusCode, String)>| result.map_err(|x| x.0);
+
+        let resolved = resolve_catalog_file(root_str, "sub/a.txt").await.unwrap();
+        assert!(resolved.ends_with("a.txt"));
+        assert!(resolve_catalog_file(root_str, "./sub/a.txt").await.is_ok());
+        assert_eq!(
+            status(resolve_catalog_file(root_str, "../secret.txt").await),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(resolve_catalog_file(root_str, "sub/../../secret.txt").await),
+            Err(StatusCode::FORBIDDEN)
+        );
+        let absolute = base.join("secret.txt");
+        assert_eq!(
+            status(resolve_catalog_file(root_str, absolute.to_str().unwrap()).await),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(resolve_catalog_file(root_str, "").await),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(resolve_catalog_file(root_str, "sub/missing.txt").await),
+            Err(StatusCode::NOT_FOUND)
+        );
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("secret.txt"), root.join("link.txt")).unwrap();
+            assert_eq!(
+                status(resolve_catalog_file(root_str, "link.txt").await),
+                Err(StatusCode::FORBIDDEN)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_digest_changed() {
+        use crate::manifest::{Manifest, ManifestEntry, MANIFEST_NAME};
+        let root = TempPath::new("etag");
+        let path = root.str();
+        std::fs::create_dir_all(path).unwrap();
+        let file_name = root.path().join("a.txt");
+        std::fs::write(&file_name, b"abc").unwrap();
+        let meta = std::fs::metadata(&file_name).unwrap();
+        let mtime = meta
+            .modified()
+            .unwrap()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let entry = ManifestEntry {
+            path: "a.txt".to_string(),
+            size: 3,
+            digest: blake3::hash(b"abc").to_hex().to_string(),
+            mtime: Some(mtime),
+            mode: None,
+            uid: None,
+            gid: None,
+            link: None,
+            chunks: Vec::new(),
+        };
+        let manifest = Manifest::new("etag", vec![entry]);
+        std::fs::write(
+            root.path().join(MANIFEST_NAME),
+            serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+        let digest = |meta| get_file_digest(path, "etag", "filelist.txt", "a.txt", meta);
+        let expected = blake3::hash(b"abc").to_hex().to_string();
+        assert_eq!(digest(&meta).await, Some(expected));
+
+        //changed after indexing, the stale digest must not allow a range of the new content
+        std::fs::write(&file_name, b"abcd").unwrap();
+        let meta = std::fs::metadata(&file_name).unwrap();
+        assert_eq!(digest(&meta).await, None);
+        let file = std::fs::File::options().write(true).open(&file_name).unwrap();
+        file.set_len(3).unwrap();
+        file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime + 10))
+            .unwrap();
+        let meta = std::fs::metadata(&file_name).unwrap();
+        assert_eq!(digest(&meta).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_catalog() {
+        let config = json!({"demo": {"path": "./demo_sent"}, "nopath": {}});
+        for catalog in ["unknown", "nopath"] {
+            let (context, limits, addr) = request_parts(config.clone());
+            let file = (catalog.to_string(), "filelist.txt".to_string());
+            let response = get_file(context, limits, addr, Path(file), HeaderMap::new()).await;
+            assert_eq!(response_text(response).await, (StatusCode::NOT_FOUND, not_found(catalog)));
+
+            let (context, limits, addr) = request_parts(config.clone());
+            let params = json!({"catalog": catalog, "file": "filelist.txt"}).to_string();
+            let params = Path(base16_encode(&params).unwrap());
+            let response = download_file(context, limits, addr, params, HeaderMap::new()).await;
+            let expected = String::from("Error：") + &not_found(catalog);
+            assert_eq!(response_text(response).await, (StatusCode::NOT_FOUND, expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_params() {
+        let config = json!({"demo": {"path": "./demo_sent"}});
+        for params in [r#"{"catalog":"demo","#, "filelist.txt"] {
+            let (context, limits, addr) = request_parts(config.clone());
+            let params = Path(base16_encode(params).unwrap());
+            let response = download_file(context, limits, addr, params, HeaderMap::new()).await;
+            let (status, text) = response_text(response).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(text.starts_with("Error：invalid params"));
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use crate::manifest::ManifestChunk;
+use anyhow::Result;
+use blake3::Hasher;
+use fastcdc::v2020::StreamCDC;
+#[cfg(feature = "download")]
+use std::collections::HashMap;
+use std::fs::File;
+use tokio::task;
+
+//Average chunk size of a catalog without "chunk_size", 0 turns chunking off
+#[cfg(feature = "digest")]
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+//Smaller files are cheaper to fetch whole than to chunk on both sides
+#[cfg(feature = "digest")]
+const MIN_CHUNKS_PER_FILE: u64 = 4;
+
+//FastCDC accepts an average chunk size of 256 bytes..4 MiB
+fn chunk_bounds(chunk_size: u64) -> (u32, u32, u32) {
+    let avg = chunk_size.clamp(256, 4 * 1024 * 1024) as u32;
+    (avg / 4, avg, avg * 4)
+}
+
+#[cfg(feature = "digest")]
+pub fn should_chunk(file_size: u64, chunk_size: u64) -> bool {
+    chunk_size > 0 && file_size >= chunk_size * MIN_CHUNKS_PER_FILE
+}
+
+//One pass over the file, return (digest of the whole file,chunks)
+pub async fn chunk_file(file_name: &str, chunk_size: u64) -> Result<(String, Vec<ManifestChunk>)> {
+    let file_name = file_name.to_string();
+    task::spawn_blocking(move || {
+        let (min_size, avg_size, max_size) = chunk_bounds(chunk_size);
+        let mut digest = Hasher::new();
+        let mut chunks = Vec::new();
+        for chunk in StreamCDC::new(File::open(&file_name)?, min_size, avg_size, max_size) {
+            let chunk = chunk?;
+            digest.update(&chunk.data);
+            chunks.push(ManifestChunk {
+                size: chunk.length as u64,
+                digest: format!("{}", blake3::hash(&chunk.data).to_hex()),
+            });
+        }
+        Ok((format!("{}", digest.finalize().to_hex()), chunks))
+    })
+    .await?
+}
+
+//Chunks of local files by digest, so a chunk anywhere on disk is copied instead of fetched.
+//Entries may be stale, whatever is read through them is checked against the chunk digest
+#[cfg(feature = "download")]
+#[derive(Default)]
+pub struct ChunkStore {
+    //digest -> (file name,offset)
+    chunks: HashMap<String, (String, u64)>,
+}
+
+#[cfg(feature = "download")]
+impl ChunkStore {
+    //the latest file added wins, it is the copy most likely still on disk
+    pub fn add_file(&mut self, file_name: &str, chunks: &[ManifestChunk]) {
+        let mut offset = 0;
+        for chunk in chunks {
+            self.chunks
+                .insert(chunk.digest.clone(), (file_name.to_string(), offset));
+            offset += chunk.size;
+        }
+    }
+
+    pub fn locate(&self, digest: &str) -> Option<(String, u64)> {
+        self.chunks.get(digest).cloned()
+    }
+
+    pub fn contains_any(&self, chunks: &[ManifestChunk]) -> bool {
+        chunks.iter().any(|x| self.chunks.contains_key(&x.digest))
+    }
+}
+
+//How the new file is built, in file order
+#[cfg(feature = "download")]
+#[derive(Debug, PartialEq)]
+pub enum Segment {
+    //bytes at source of a local file, written at target
+    Reuse {
+        file: String,
+        source: u64,
+        target: u64,
+        size: u64,
+    },
+    //a range of the remote file, written at the same offset
+    Fetch {
+        skip: u64,
+        take: u64,
+    },
+}
+
+//Match the remote chunks against chunks found on disk by locate, neighbouring
+//segments are merged up to max_size so one request or read covers several chunks
+#[cfg(feature = "download")]
+pub fn delta_segments(
+    remote: &[ManifestChunk],
+    locate: impl Fn(&str) -> Option<(String, u64)>,
+    max_size: u64,
+) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut target = 0;
+    for chunk in remote {
+        let size = chunk.size;
+        match (locate(&chunk.digest), segments.last_mut()) {
+            (
+                Some((file, source)),
+                Some(Segment::Reuse {
+                    file: last_file,
+                    source: last_source,
+                    size: last_size,
+                    ..
+                }),
+            ) if *last_file == file
+                && *last_source + *last_size == source
+                && *last_size + size <= max_size =>
+            {
+                *last_size += size
+            }
+            (Some((file, source)), _) => segments.push(Segment::Reuse {
+                file,
+                source,
+                target,
+                size,
+            }),
+            (None, Some(Segment::Fetch { take, .. })) if *take + size <= max_size => *take += size,
+            (None, _) => segments.push(Segment::Fetch {
+                skip: target,
+                take: size,
+            }),
+        }
+        target += size;
+    }
+    segments
+}
+
+//Whether bytes read for the range at offset still hash to the remote chunks of that range,
+//offsets are the start of each chunk
+#[cfg(feature = "download")]
+pub fn matches_chunks(
+    chunks: &[ManifestChunk],
+    offsets: &[u64],
+    offset: u64,
+    size: u64,
+    bytes: &[u8],
+) -> bool {
+    if bytes.len() as u64 != size {
+        return false;
+    }
+    let end = offset + size;
+    let mut covered = offset;
+    for i in offsets.partition_point(|x| *x < offset)..chunks.len() {
+        let chunk_end = offsets[i] + chunks[i].size;
+        if offsets[i] != covered || chunk_end > end {
+            break;
+        }
+        let slice = &bytes[(offsets[i] - offset) as usize..(chunk_end - offset) as usize];
+        if blake3::hash(slice).to_hex().as_str() != chunks[i].digest {
+            return false;
+        }
+        covered = chunk_end;
+    }
+    covered == end
+}
+
+#[cfg(all(test, feature = "download"))]
+mod tests {
+    use super::*;
+    use crate::fileutil::TempPath;
+
+    fn chunk(size: u64, digest: &str) -> ManifestChunk {
+        ManifestChunk {
+            size,
+            digest: digest.to_string(),
+        }
+    }
+
+    //chunks a,b,c,d of 4 bytes each in one local file
+    fn locate(digest: &str) -> Option<(String, u64)> {
+        let offset = ["a", "b", "c", "d"].iter().position(|x| *x == digest)?;
+        Some(("old".to_string(), offset as u64 * 4))
+    }
+
+    fn reuse(source: u64, target: u64, size: u64) -> Segment {
+        Segment::Reuse {
+            file: "old".to_string(),
+            source,
+            target,
+            size,
+        }
+    }
+
+    #[test]
+    fn test_delta_segments() {
+        let remote = [
+            chunk(4, "a"),
+            chunk(4, "b"),
+            chunk(2, "x"),
+            chunk(2, "y"),
+            chunk(4, "d"),
+            chunk(4, "a"),
+        ];
+        assert_eq!(
+            delta_segments(&remote, locate, 100),
+            vec![
+                reuse(0, 0, 8),
+                Segment::Fetch { skip: 8, take: 4 },
+                reuse(12, 12, 4),
+                reuse(0, 16, 4),
+            ]
+        );
+        //merged segments stay within max_size
+        assert_eq!(
+            delta_segments(&remote, locate, 4)[..3],
+            [
+                reuse(0, 0, 4),
+                reuse(4, 4, 4),
+                Segment::Fetch { skip: 8, take: 4 },
+            ]
+        );
+        assert_eq!(
+            delta_segments(&remote, |_| None, 100),
+            vec![Segment::Fetch { skip: 0, take: 20 }]
+        );
+    }
+
+    #[test]
+    fn test_matches_chunks() {
+        let hash = |x: &[u8]| format!("{}", blake3::hash(x).to_hex());
+        let chunks = [
+            chunk(2, &hash(b"ab")),
+            chunk(3, &hash(b"cde")),
+            chunk(1, &hash(b"f")),
+        ];
+        let offsets = [0, 2, 5];
+        assert!(matches_chunks(&chunks, &offsets, 2, 4, b"cdef"));
+        assert!(matches_chunks(&chunks, &offsets, 0, 6, b"abcdef"));
+        //changed since the chunks were recorded, or cut short
+        assert!(!matches_chunks(&chunks, &offsets, 2, 4, b"cdeX"));
+        assert!(!matches_chunks(&chunks, &offsets, 2, 4, b"cde"));
+        //not on chunk boundaries
+        assert!(!matches_chunks(&chunks, &offsets, 1, 4, b"bcde"));
+    }
+
+    #[tokio::test]
+    async fn test_chunk_file() {
+        let file = TempPath::new("chunked.bin");
+        let file_name = file.str();
+        let mut seed: u64 = 1;
+        let mut data: Vec<u8> = (0..256 * 1024)
+            .map(|_| {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (seed >> 56) as u8
+            })
+            .collect();
+        std::fs::write(file_name, &data).unwrap();
+        let (digest, old_chunks) = chunk_file(file_name, 4096).await.unwrap();
+        assert_eq!(digest, format!("{}", blake3::hash(&data).to_hex()));
+        assert_eq!(
+            old_chunks.iter().map(|x| x.size).sum::<u64>(),
+            data.len() as u64
+        );
+        assert!(old_chunks.len() > 16);
+
+        //an insert in the middle only changes the chunks around it
+        data.splice(100_000..100_000, *b"inserted");
+        std::fs::write(file_name, &data).unwrap();
+        let (_, new_chunks) = chunk_file(file_name, 4096).await.unwrap();
+        let mut old_copy = ChunkStore::default();
+        old_copy.add_file("old", &old_chunks);
+        let fetched = delta_segments(&new_chunks, |x| old_copy.locate(x), u64::MAX)
+            .iter()
+            .map(|x| match x {
+                Segment::Fetch { take, .. } => *take,
+                Segment::Reuse { .. } => 0,
+            })
+            .sum::<u64>();
+        assert!(fetched > 0 && fetched < 4 * 16384, "fetched {}", fetched);
+    }
+}
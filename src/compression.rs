@@ -0,0 +1,141 @@
+#[cfg(feature = "server")]
+use serde_json::Value;
+#[cfg(feature = "server")]
+use std::path::Path;
+
+//Sent by the client, zstd is preferred when the server offers both
+#[cfg(feature = "download")]
+pub const ACCEPT_ENCODING: &str = "zstd, gzip";
+//Parts this small are sent as they are, compression would not pay for itself
+#[cfg(feature = "server")]
+pub const MIN_COMPRESS_SIZE: u64 = 1024;
+//Already compressed formats, used unless the catalog sets "no_compress_extensions"
+#[cfg(feature = "server")]
+const NO_COMPRESS_EXTENSIONS: [&str; 16] = [
+    "7z", "bz2", "cab", "gif", "gz", "jpeg", "jpg", "mkv", "mp3", "mp4", "png", "rar", "webp",
+    "xz", "zip", "zst",
+];
+
+//Pick zstd or gzip from an Accept-Encoding header, None sends identity
+#[cfg(feature = "server")]
+pub fn choose_encoding(accept_encoding: &str) -> Option<&'static str> {
+    let accepted = |name: &str| {
+        accept_encoding.split(',').any(|x| {
+            let mut parts = x.split(';');
+            let coding = parts.next().unwrap_or("").trim();
+            //"q=0" means not acceptable
+            let refused = parts.any(|x| {
+                x.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            coding.eq_ignore_ascii_case(name) && !refused
+        })
+    };
+    ["zstd", "gzip"].into_iter().find(|x| accepted(x))
+}
+
+//Catalog config key: "no_compress_extensions": ["zip","mp4",...] replaces the default list
+#[cfg(feature = "server")]
+pub fn is_compressible(catalog_config: &Value, file: &str) -> bool {
+    let extension = match Path::new(file).extension().and_then(|x| x.to_str()) {
+        Some(extension) => extension.to_lowercase(),
+        None => return true,
+    };
+    match catalog_config["no_compress_extensions"].as_array() {
+        Some(extensions) => !extensions
+            .iter()
+            .filter_map(|x| x.as_str())
+            .any(|x| x.trim_start_matches('.').eq_ignore_ascii_case(&extension)),
+        None => !NO_COMPRESS_EXTENSIONS.contains(&extension.as_str()),
+    }
+}
+
+#[cfg(feature = "server")]
+pub fn encode<R>(reader: R, encoding: &str) -> Box<dyn tokio::io::AsyncRead + Send + Unpin>
+where
+    R: tokio::io::AsyncRead + Send + Unpin + 'static,
+{
+    use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
+    use async_compression::Level;
+    use tokio::io::BufReader;
+    let reader = BufReader::new(reader);
+    //fast levels, the network is the bottleneck only on slow links
+    match encoding {
+        "zstd" => Box::new(ZstdEncoder::with_quality(reader, Level::Precise(3))),
+        "gzip" => Box::new(GzipEncoder::with_quality(reader, Level::Fastest)),
+        _ => Box::new(reader),
+    }
+}
+
+//Decode a response body of at most limit bytes, the x-take of the server,
+//a body inflating beyond it is refused rather than held in memory
+#[cfg(feature = "download")]
+pub async fn decode(bytes: Vec<u8>, encoding: &str, limit: u64) -> anyhow::Result<Vec<u8>> {
+    use anyhow::anyhow;
+    use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+    use tokio::io::AsyncReadExt;
+    let mut result = Vec::new();
+    match encoding {
+        "" | "identity" => return Ok(bytes),
+        "zstd" => {
+            let decoder = ZstdDecoder::new(bytes.as_slice());
+            decoder.take(limit + 1).read_to_end(&mut result).await?
+        }
+        "gzip" => {
+            let decoder = GzipDecoder::new(bytes.as_slice());
+            decoder.take(limit + 1).read_to_end(&mut result).await?
+        }
+        _ => return Err(anyhow!("unsupported content-encoding {}", encoding)),
+    };
+    if result.len() as u64 > limit {
+        return Err(anyhow!("{} body decodes to more than {} bytes", encoding, limit));
+    }
+    Ok(result)
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+
+    #[cfg(all(feature = "server", feature = "download"))]
+    #[test]
+    fn test_choose_encoding() {
+        assert_eq!(choose_encoding(ACCEPT_ENCODING), Some("zstd"));
+        assert_eq!(choose_encoding("gzip, deflate, br"), Some("gzip"));
+        assert_eq!(choose_encoding("zstd;q=0, GZIP;q=0.5"), Some("gzip"));
+        assert_eq!(choose_encoding("br"), None);
+        assert_eq!(choose_encoding(""), None);
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        let default = serde_json::json!({});
+        assert!(is_compressible(&default, "bin/app.dll"));
+        assert!(is_compressible(&default, "README"));
+        assert!(!is_compressible(&default, "setup/Installer.ZIP"));
+        let custom = serde_json::json!({"no_compress_extensions": [".dll"]});
+        assert!(!is_compressible(&custom, "bin/app.dll"));
+        assert!(is_compressible(&custom, "setup/installer.zip"));
+    }
+
+    #[cfg(all(feature = "server", feature = "download"))]
+    #[tokio::test]
+    async fn test_encode_decode() {
+        use tokio::io::AsyncReadExt;
+        let data = b"filer ".repeat(1000);
+        for encoding in ["zstd", "gzip"] {
+            let mut encoded = Vec::new();
+            encode(std::io::Cursor::new(data.clone()), encoding)
+                .read_to_end(&mut encoded)
+                .await
+                .unwrap();
+            assert!(encoded.len() < data.len() / 10);
+            let limit = data.len() as u64;
+            assert!(decode(encoded.clone(), encoding, limit - 1).await.is_err());
+            assert_eq!(decode(encoded, encoding, limit).await.unwrap(), data);
+        }
+        assert!(decode(data.clone(), "br", 0).await.is_err());
+    }
+}
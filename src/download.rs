@@ -1,15 +1,37 @@
 use crate::base16::base16_encode;
+use crate::compression::{decode, ACCEPT_ENCODING};
+use crate::chunking::{chunk_file, delta_segments, matches_chunks, ChunkStore, Segment};
 use crate::fileutil;
+use crate::filter::FileFilter;
+use crate::hooks::{HookEnv, Hooks};
 use crate::json_helper::JsonHelper;
+use crate::manifest::{
+    load_manifest, parse_line, Manifest, ManifestChunk, LEGACY_LIST_NAME, MANIFEST_NAME,
+};
+use crate::metadata::{restore_link, restore_metadata, FileMeta};
+use crate::plan::{sync_plan, SyncPlan};
+use crate::process::stop_running_files;
+use crate::rate_limit::RateLimiter;
+use crate::scheduler::Scheduler;
+use crate::self_update::{install_binaries, restart};
+use crate::signing::{verify, SIGNATURE_SUFFIX};
 use anyhow::{anyhow, Result};
 use blake3::Hasher;
 use byte_unit::Byte;
-use fileutil::{calc_parts, kill_running_exe, PartData, EOL, MAX_SPLIT_PARTS};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use fileutil::{
+    calc_parts, replace_with_temp, write_file_atomic, PartData, EOL,
+    MAX_SPLIT_PARTS, TEMP_SUFFIX,
+};
 use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::io::SeekFrom;
 use std::path::Path;
-use tokio::fs::{self, DirBuilder, File};
+use std::time::Duration;
+use std::sync::{Arc, RwLock};
+use tokio::fs::{self, DirBuilder, File, OpenOptions};
 use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::task;
 use std::io;
@@ -26,75 +48,392 @@ pub fn base_url(config: &Value) -> String {
         port
     )
 }
-pub async fn request(base_url: &str, params: &Value) -> Result<Response> {
-    //use tracing::debug;
-    let params = base16_encode(&format!("{}", params))?;
-    let url = String::from(base_url) + &params;
-    //debug!("get {}", url);
-    reqwest::get(url)
-        .await
-        .map_err(|e| anyhow!("download::request error {:?}", e))
+
+//Shared by every request of a run: one pooled client keeping connections alive
+//(HTTP/2 when the server offers it), the server, the catalog and the client "rate_limit"
+pub struct DownloadContext {
+    client: reqwest::Client,
+    base_url: String,
+    token: String,
+    catalog: String,
+    rate_limit: Option<RateLimiter>,
+    retry: RetryPolicy,
+}
+
+impl DownloadContext {
+    //Client config: "ca_cert" trusts a self-signed server.cer, "http2" speaks HTTP/2 to a plain
+    //http server without negotiation, "connect_timeout" and "timeout" (0 is none) in seconds
+    pub async fn new(client_config: &Value, catalog: &str) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(format!("filer/{}", crate::VERSION))
+            .connect_timeout(Duration::from_secs(client_config["connect_timeout"].u64(10)))
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(60))
+            .tcp_nodelay(true)
+            .http2_adaptive_window(true);
+        let timeout = client_config["timeout"].u64(600);
+        if timeout > 0 {
+            builder = builder.timeout(Duration::from_secs(timeout));
+        }
+        if client_config["http2"].bool(false) {
+            builder = builder.http2_prior_knowledge();
+        }
+        let ca_cert = client_config["ca_cert"].str("");
+        if !ca_cert.is_empty() {
+            let bytes = fs::read(ca_cert)
+                .await
+                .map_err(|e| anyhow!("read ca_cert {} fail: {}", ca_cert, e))?;
+            let cert = reqwest::Certificate::from_pem(&bytes)
+                .or_else(|_| reqwest::Certificate::from_der(&bytes))
+                .map_err(|e| anyhow!("ca_cert {} is not a PEM or DER certificate: {}", ca_cert, e))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        Ok(DownloadContext {
+            client: builder.build()?,
+            base_url: base_url(client_config),
+            token: client_config["token"].string(""),
+            catalog: catalog.to_string(),
+            rate_limit: RateLimiter::from_config(&client_config["rate_limit"])?,
+            retry: RetryPolicy::from_config(client_config),
+        })
+    }
+
+    async fn request(&self, params: &Value) -> Result<Response> {
+        //use tracing::debug;
+        let params = base16_encode(&format!("{}", params))?;
+        let url = self.base_url.clone() + &params;
+        //debug!("get {}", url);
+        let request = self
+            .client
+            .get(url)
+            .header(reqwest::header::ACCEPT_ENCODING, ACCEPT_ENCODING);
+        let request = if self.token.is_empty() {
+            request
+        } else {
+            request.bearer_auth(&self.token)
+        };
+        request
+            .send()
+            .await
+            .map_err(|e| anyhow!("download::request error {:?}", e))
+    }
+}
+
+//Client config "retries" of a failed part, the wait starts around "retry_delay" and doubles
+//up to "retry_max_delay", both in milliseconds
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    retries: u64,
+    delay: u64,
+    max_delay: u64,
 }
 
-pub async fn get_full_of_file(base_url: &str, catalog: &str, file: &str) -> Result<PartData> {
-    get_part_of_file(base_url, catalog, file, 0, 0).await
+impl RetryPolicy {
+    fn from_config(client_config: &Value) -> Self {
+        RetryPolicy {
+            retries: client_config["retries"].u64(4),
+            delay: client_config["retry_delay"].u64(500),
+            max_delay: client_config["retry_max_delay"].u64(30_000),
+        }
+    }
+
+    //half of the doubled delay plus a random share of the other half,
+    //so parts failing together do not hit the server again in step
+    fn backoff(&self, attempt: u64, random: u64) -> Duration {
+        let delay = self
+            .delay
+            .saturating_mul(1 << attempt.min(20))
+            .min(self.max_delay);
+        Duration::from_millis(delay / 2 + random % (delay / 2 + 1))
+    }
+}
+
+fn random() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    RandomState::new().build_hasher().finish()
+}
+
+//The server turned the request down, asking again gets the same answer
+#[derive(Debug)]
+struct Refused(String);
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+pub async fn get_full_of_file(context: &DownloadContext, file: &str) -> Result<PartData> {
+    get_part_of_file(context, file, 0, 0).await
 }
 
+//Network errors, cut off bodies and server errors are retried with backoff
 pub async fn get_part_of_file(
-    base_url: &str,
-    catalog: &str,
+    context: &DownloadContext,
     file: &str,
     skip: u64,
     take: u64,
 ) -> Result<PartData> {
+    let mut attempt = 0;
+    loop {
+        match fetch_part_of_file(context, file, skip, take).await {
+            Err(e) if attempt < context.retry.retries && e.downcast_ref::<Refused>().is_none() => {
+                let wait = context.retry.backoff(attempt, random());
+                println!("\n>>retry {} at {} in {:?}: {}", file, skip, wait, e);
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn fetch_part_of_file(
+    context: &DownloadContext,
+    file: &str,
+    skip: u64,
+    take: u64,
+) -> Result<PartData> {
+    let catalog = &context.catalog;
     let params = if take == 0 {
         json!({"catalog":catalog,"file":file})
     } else {
         json!({"catalog":catalog,"file":file,"skip":skip,"take":take})
     };
-    let response = request(base_url, &params).await?;
+    let mut response = context.request(&params).await?;
     if response.status() == StatusCode::OK {
         let headers = response.headers();
         let skip_val = headers.get("x-skip");
-        let skip = if skip_val.is_none() {
-            skip
+        let skip = if let Some(skip_val) = skip_val {
+            //u64::from_str_radix(skip_val.to_str()?, 10)?
+            (skip_val.to_str()?).parse::<u64>()?
         } else {
-            //u64::from_str_radix(skip_val.unwrap().to_str()?, 10)?
-            (skip_val.unwrap().to_str()?).parse::<u64>()?
+            skip
         };
         let take_val = headers.get("x-take");
-        let take = if take_val.is_none() {
+        let take = if let Some(take_val) = take_val {
+            //u64::from_str_radix(take_val.to_str()?, 10)?
+            (take_val.to_str()?).parse::<u64>()?
+        } else {
             take
+        };
+        let encoding = headers
+            .get(reqwest::header::CONTENT_ENCODING)
+            .map(|x| x.to_str().unwrap_or("?").to_string())
+            .unwrap_or_default();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if let Some(rate_limit) = &context.rate_limit {
+                rate_limit.acquire(chunk.len() as u64).await;
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        let bytes = decode(bytes, &encoding, take).await?;
+        Ok((skip, take, bytes))
+    } else {
+        let status = response.status();
+        let msg = if response.headers().contains_key("x-body-is-error") {
+            let msg = response.bytes().await?;
+            format!("download files fail: {}", String::from_utf8(msg.to_vec())?)
         } else {
-            //u64::from_str_radix(take_val.unwrap().to_str()?, 10)?
-            (take_val.unwrap().to_str()?).parse::<u64>()?
+            format!("download files fail: unkown reason {:?}", status)
         };
-        io::stdout().flush()?;
+        //a busy or failing server may answer differently next time
+        let refused = status.is_client_error()
+            && status != StatusCode::REQUEST_TIMEOUT
+            && status != StatusCode::TOO_MANY_REQUESTS;
+        if refused {
+            Err(Refused(msg).into())
+        } else {
+            Err(anyhow!(msg))
+        }
+    }
+}
+//One # per part fetched from the server
+fn print_progress(part: PartData) -> PartData {
+    let _ = io::stdout().flush();
+    print!("#");
+    part
+}
 
-        print!("#");
+//Sidecar of an interrupted download, <target>.filer-partial
+pub const PARTIAL_SUFFIX: &str = ".filer-partial";
+//A patched file is built in <target>.delta.filer-tmp, the temp file a part journal
+//resumes is left alone so a failed patch can fall back to it
+const DELTA_SUFFIX: &str = ".delta";
 
-        let bytes = response.bytes().await?.to_vec();
-        Ok((skip, take, bytes))
-    } else if response.status() == StatusCode::NOT_ACCEPTABLE
-        && response.headers().contains_key("x-body-is-error")
-    {
-        let msg = response.bytes().await?;
-        let msg: String = String::from_utf8(msg.to_vec())?;
-        Err(anyhow!("download files fail: {}", msg))
-    } else {
-        Err(anyhow!(
-            "download files fail: unkown reason {:?}",
-            response.status()
-        ))
+//Part journal of a multi-part download, records parts already written and verified
+#[derive(Serialize, Deserialize, Default)]
+struct PartJournal {
+    digest: String,
+    size: u64,
+    part_size: u64,
+    //part index -> blake3 digest of the part written to disk
+    parts: BTreeMap<u64, String>,
+}
+impl PartJournal {
+    fn new(digest: &str, size: u64, part_size: u64) -> Self {
+        PartJournal {
+            digest: digest.to_string(),
+            size,
+            part_size,
+            parts: BTreeMap::new(),
+        }
+    }
+    //load the journal if it describes the same remote file, otherwise start a new one
+    async fn load(journal_name: &str, digest: &str, size: u64, part_size: u64) -> Self {
+        let journal = fs::read_to_string(journal_name)
+            .await
+            .ok()
+            .and_then(|x| serde_json::from_str::<PartJournal>(&x).ok());
+        match journal {
+            Some(journal)
+                if journal.digest == digest
+                    && journal.size == size
+                    && journal.part_size == part_size =>
+            {
+                journal
+            }
+            _ => Self::new(digest, size, part_size),
+        }
+    }
+    //write aside and rename, a crash never leaves a torn journal behind
+    async fn save(&self, journal_name: &str) -> Result<()> {
+        let temp_name = String::from(journal_name) + ".tmp";
+        fs::write(&temp_name, serde_json::to_string(self)?).await?;
+        fs::rename(&temp_name, journal_name).await?;
+        Ok(())
+    }
+}
+
+//return (digest_calc,file_size_calc,parts,part_size,from_local,resumed_parts,reused_size)
+type DowloadFileReturn = (String, u64, u64, u64, bool, u64, u64);
+
+//Build the temp file from chunks already on disk, in the old target or any other local
+//file, and fetch only the others. return (..,reused_size) as bytes not fetched
+#[allow(clippy::too_many_arguments)]
+async fn delta_file(
+    context: &Arc<DownloadContext>,
+    source_file_name: &str,
+    target_file_name: &str,
+    file_size: u64,
+    part_size: u64,
+    digest: &str,
+    chunks: &[ManifestChunk],
+    chunk_size: u64,
+    has_old_copy: bool,
+    store: &RwLock<ChunkStore>,
+    scheduler: &Scheduler,
+) -> Result<DowloadFileReturn> {
+    let temp_file_name = String::from(target_file_name) + DELTA_SUFFIX + TEMP_SUFFIX;
+    let mut old_copy = ChunkStore::default();
+    if has_old_copy {
+        old_copy.add_file(target_file_name, &chunk_file(target_file_name, chunk_size).await?.1);
+    }
+    let segments = {
+        let store = store.read().map_err(|_| anyhow!("chunk store poisoned"))?;
+        let locate = |x: &str| old_copy.locate(x).or_else(|| store.locate(x));
+        delta_segments(chunks, locate, part_size)
+    };
+    let offsets: Vec<u64> = chunks
+        .iter()
+        .scan(0, |offset, x| {
+            *offset += x.size;
+            Some(*offset - x.size)
+        })
+        .collect();
+    let fetch = |&(skip, take): &(u64, u64)| {
+        let context = context.clone();
+        let source_file_name: String = source_file_name.into();
+        scheduler.spawn(async move {
+            get_part_of_file(&context, &source_file_name, skip, take)
+                .await
+                .map(print_progress)
+        })
+    };
+    let ranges: Vec<(u64, u64)> = segments
+        .iter()
+        .filter_map(|x| match x {
+            Segment::Fetch { skip, take } => Some((*skip, *take)),
+            Segment::Reuse { .. } => None,
+        })
+        .collect();
+    //keep at most MAX_SPLIT_PARTS requests ahead of the writer
+    let mut pending = ranges.iter().map(&fetch);
+    let mut in_flight: VecDeque<_> = pending.by_ref().take(MAX_SPLIT_PARTS as usize).collect();
+    let mut target = File::create(&temp_file_name).await?;
+    let mut digest_calc = Hasher::new();
+    let mut file_size_calc: u64 = 0;
+    let mut reused_size: u64 = 0;
+    let mut parts = ranges.len() as u64;
+    let result: Result<()> = async {
+        for segment in &segments {
+            let bytes = match *segment {
+                Segment::Reuse {
+                    ref file,
+                    source,
+                    target,
+                    size,
+                } => {
+                    let bytes = fileutil::get_part_of_file(file, source, size)
+                        .await
+                        .map(|x| x.2)
+                        .unwrap_or_default();
+                    if matches_chunks(chunks, &offsets, target, size, &bytes) {
+                        reused_size += size;
+                        bytes
+                    } else {
+                        //the local copy changed or is gone, fetch the range after all
+                        parts += 1;
+                        fetch(&(target, size)).await??.2
+                    }
+                }
+                Segment::Fetch { .. } => {
+                    let result = in_flight.pop_front().ok_or_else(|| anyhow!("lost a fetch"))?;
+                    in_flight.extend(pending.next());
+                    result.await??.2
+                }
+            };
+            target.write_all(&bytes).await?;
+            digest_calc.update(&bytes);
+            file_size_calc += bytes.len() as u64;
+        }
+        Ok(())
+    }
+    .await;
+    //fetches still queued would only use bandwidth and scheduler permits
+    if result.is_err() {
+        for fetch in &in_flight {
+            fetch.abort();
+        }
+    }
+    let digest_calc = format!("{}", digest_calc.finalize().to_hex());
+    let check = result.and_then(|_| {
+        if file_size_calc != file_size || digest_calc != digest {
+            Err(anyhow!("patched file does not match {}", digest))
+        } else {
+            Ok(())
+        }
+    });
+    match check {
+        Ok(_) => {
+            replace_with_temp(target, &temp_file_name, target_file_name).await?;
+            Ok((digest_calc, file_size_calc, parts, part_size, false, 0, reused_size))
+        }
+        Err(e) => {
+            drop(target);
+            let _ = fs::remove_file(&temp_file_name).await;
+            Err(e)
+        }
     }
 }
-//return (digest_calc,file_size_calc,parts,part_size,from_local)
-type DowloadFileReturn = (String, u64, u64, u64, bool);
 
 #[allow(clippy::too_many_arguments)]
 async fn download_file(
-    base_url: &str,
-    catalog: &str,
+    context: &Arc<DownloadContext>,
     path: &str,
     file_name: &str,
     file_size: u64,
@@ -102,14 +441,15 @@ async fn download_file(
     digest: &str,
     source_file_name: &str,
     from_local: bool,
+    chunks: &[ManifestChunk],
+    chunk_size: u64,
+    store: &RwLock<ChunkStore>,
+    scheduler: &Scheduler,
 ) -> Result<DowloadFileReturn> {
     let local_source_file_name = path.to_string() + "/" + source_file_name;
     let source_file_name = String::from(source_file_name);
-    let target_file_name = if file_name.to_lowercase().ends_with("filer.exe") {
-        String::from(path) + "/" + file_name + ".new"
-    } else {
-        String::from(path) + "/" + file_name
-    };
+    let target_file_name = fileutil::target_file_name(path, file_name);
+    let temp_file_name = target_file_name.clone() + TEMP_SUFFIX;
     let (parts, part_size) = calc_parts(file_size, part_size, MAX_SPLIT_PARTS);
     // println!(
     //     ">>writing {} with size {},splited {} parts*{} ...",
@@ -118,61 +458,151 @@ async fn download_file(
     let target_file_folder = Path::new(&target_file_name)
         .parent()
         .ok_or_else(|| anyhow!("get target file folder fail"))?;
+    //a link left by an earlier run must not carry the file out of path
+    fileutil::check_inside(path, &target_file_name)?;
     DirBuilder::new()
         .recursive(true)
         .create(target_file_folder)
         .await?;
-    let mut target = File::create(&target_file_name).await?;
+    let journal_name = target_file_name.clone() + PARTIAL_SUFFIX;
+    //a chunked file is patched from chunks on disk, any trouble falls back to a full download
+    let has_old_copy = fs::metadata(&target_file_name)
+        .await
+        .map(|x| x.is_file() && x.len() > 0)
+        .unwrap_or(false);
+    let in_store = store.read().map(|x| x.contains_any(chunks)).unwrap_or(false);
+    if !from_local && !chunks.is_empty() && (has_old_copy || in_store) {
+        let result = delta_file(
+            context,
+            &source_file_name,
+            &target_file_name,
+            file_size,
+            part_size,
+            digest,
+            chunks,
+            chunk_size,
+            has_old_copy,
+            store,
+            scheduler,
+        )
+        .await;
+        if result.is_ok() {
+            let _ = fs::remove_file(&journal_name).await;
+            return result;
+        }
+    }
+    //only remote multi-part downloads are worth resuming
+    let use_journal = parts > 1 && !from_local;
+    let mut journal = if use_journal {
+        PartJournal::load(&journal_name, digest, file_size, part_size).await
+    } else {
+        PartJournal::new(digest, file_size, part_size)
+    };
+    //everything is written to the temp file, the target is only replaced once the checks pass
+    let mut target = if journal.parts.is_empty() {
+        File::create(&temp_file_name).await?
+    } else {
+        match OpenOptions::new().write(true).open(&temp_file_name).await {
+            Ok(target) => target,
+            Err(_) => {
+                journal.parts.clear();
+                File::create(&temp_file_name).await?
+            }
+        }
+    };
     let mut digest_calc = Hasher::new();
     let mut file_size_calc: u64 = 0;
+    let mut resumed_parts: u64 = 0;
     async fn process_result(
         result: Result<PartData>,
-        target: &mut File,
+        target: Option<&mut File>,
         digest: &mut Hasher,
         file_size: &mut u64,
-    ) -> Result<()> {
+    ) -> Result<String> {
         let (skip, take, result) = result?;
-        target.seek(SeekFrom::Start(skip)).await?;
-        target.write_all(&result).await?;
+        if let Some(target) = target {
+            target.seek(SeekFrom::Start(skip)).await?;
+            target.write_all(&result).await?;
+        }
         digest.update(&result);
         *file_size += take;
-        Ok(())
+        Ok(format!("{}", blake3::hash(&result).to_hex()))
     }
     if parts == 1 {
-        let result = if from_local {
-            fileutil::get_full_of_file(&local_source_file_name).await
-        } else {
-            get_full_of_file(base_url, catalog, &source_file_name).await
-        };
-        process_result(result, &mut target, &mut digest_calc, &mut file_size_calc).await?;
+        let result = scheduler
+            .run(async {
+                if from_local {
+                    fileutil::get_full_of_file(&local_source_file_name).await
+                } else {
+                    get_full_of_file(context, &source_file_name)
+                        .await
+                        .map(print_progress)
+                }
+            })
+            .await;
+        process_result(result, Some(&mut target), &mut digest_calc, &mut file_size_calc).await?;
     } else {
-        let mut results: Vec<task::JoinHandle<Result<PartData>>> =
-            Vec::with_capacity(parts as usize);
-        for i in 0..parts as usize {
-            let skip = i as u64 * part_size;
-            let take = part_size;
+        let get_part = |skip: u64, take: u64| {
             let source_file_name = source_file_name.clone();
             let local_source_file_name = local_source_file_name.clone();
-            let base_url: String = base_url.into();
-            let catalog: String = catalog.into();
-            results.push(task::spawn(async move {
+            let context = context.clone();
+            async move {
                 if from_local {
                     fileutil::get_part_of_file(&local_source_file_name, skip, take).await
                 } else {
-                    get_part_of_file(&base_url, &catalog, &source_file_name, skip, take).await
+                    get_part_of_file(&context, &source_file_name, skip, take)
+                        .await
+                        .map(print_progress)
                 }
-            }));
+            }
+        };
+        let mut results: Vec<Option<task::JoinHandle<Result<PartData>>>> =
+            Vec::with_capacity(parts as usize);
+        for i in 0..parts {
+            if journal.parts.contains_key(&i) {
+                results.push(None);
+            } else {
+                results.push(Some(scheduler.spawn(get_part(i * part_size, part_size))));
+            }
         }
-        for result in results {
-            let result = result.await?;
-            process_result(result, &mut target, &mut digest_calc, &mut file_size_calc).await?;
+        for (i, result) in (0..parts).zip(results) {
+            let skip = i * part_size;
+            if result.is_none() {
+                //part written by an earlier run, reuse it while it still matches the journal
+                let part = fileutil::get_part_of_file(&temp_file_name, skip, part_size).await;
+                if let Ok(part) = part {
+                    let part_digest = format!("{}", blake3::hash(&part.2).to_hex());
+                    if journal.parts.get(&i) == Some(&part_digest) {
+                        process_result(Ok(part), None, &mut digest_calc, &mut file_size_calc)
+                            .await?;
+                        resumed_parts += 1;
+                        continue;
+                    }
+                }
+            }
+            let result = match result {
+                Some(result) => result.await?,
+                None => scheduler.run(get_part(skip, part_size)).await,
+            };
+            let part_digest =
+                process_result(result, Some(&mut target), &mut digest_calc, &mut file_size_calc)
+                    .await?;
+            if use_journal {
+                target.flush().await?;
+                journal.parts.insert(i, part_digest);
+                journal.save(&journal_name).await?;
+            }
         }
     }
 
     let digest_calc = digest_calc.finalize();
     let digest_calc = format!("{}", digest_calc.to_hex());
 
-    if file_size_calc != file_size {
+    //the journal is only kept while the download can still be resumed
+    if use_journal {
+        let _ = fs::remove_file(&journal_name).await;
+    }
+    let check = if file_size_calc != file_size {
         Err(anyhow!(
             "file size check error, expect: {}, got: {}",
             file_size,
@@ -185,235 +615,595 @@ async fn download_file(
             digest_calc
         ))
     } else {
-        Ok((digest_calc, file_size_calc, parts, part_size, from_local))
+        Ok(())
+    };
+    match check {
+        Ok(_) => {
+            replace_with_temp(target, &temp_file_name, &target_file_name).await?;
+            Ok((digest_calc, file_size_calc, parts, part_size, from_local, resumed_parts, 0))
+        }
+        Err(e) => {
+            drop(target);
+            let _ = fs::remove_file(&temp_file_name).await;
+            Err(e)
+        }
+    }
+}
+
+//Fetch the remote manifest, falling back to the legacy list of older servers.
+//With a pinned public key only a manifest signed by that key is accepted
+pub async fn get_manifest(context: &DownloadContext, public_key: &str) -> Result<Manifest> {
+    let (base_url, catalog) = (&context.base_url, &context.catalog);
+    if !public_key.is_empty() {
+        return get_signed_manifest(context, public_key).await;
     }
+    let (_, _, bytes) = match get_full_of_file(context, MANIFEST_NAME).await {
+        Ok(part) => part,
+        Err(_) => get_full_of_file(context, LEGACY_LIST_NAME).await?,
+    };
+    Manifest::parse(catalog, &bytes)
+        .map_err(|e| anyhow!("{} manifest of {}: {}", base_url, catalog, e))
+}
+
+//The signature covers the exact bytes served, so it is checked before parsing
+async fn get_signed_manifest(context: &DownloadContext, public_key: &str) -> Result<Manifest> {
+    let (base_url, catalog) = (&context.base_url, &context.catalog);
+    let (_, _, bytes) = get_full_of_file(context, MANIFEST_NAME).await?;
+    let signature_name = String::from(MANIFEST_NAME) + SIGNATURE_SUFFIX;
+    let (_, _, signature) = get_full_of_file(context, &signature_name)
+        .await
+        .map_err(|e| anyhow!("{} manifest of {} is not signed: {}", base_url, catalog, e))?;
+    let signature = String::from_utf8_lossy(&signature);
+    verify(public_key, &bytes, &signature)
+        .map_err(|e| anyhow!("{} manifest of {} rejected: {}", base_url, catalog, e))?;
+    let manifest = Manifest::parse(catalog, &bytes)
+        .map_err(|e| anyhow!("{} manifest of {}: {}", base_url, catalog, e))?;
+    //a valid signature of another catalog must not be replayed here
+    if &manifest.header.catalog != catalog {
+        return Err(anyhow!(
+            "{} manifest of {} is signed for catalog {}",
+            base_url,
+            catalog,
+            manifest.header.catalog
+        ));
+    }
+    Ok(manifest)
+}
+
+//Fetch the remote manifest, read the local manifest and the run journal
+//Both manifests hold only the files the filter lets through, files left out are
+//neither downloaded nor pruned and the manifest written at the end does not list them
+async fn read_file_lists(
+    context: &DownloadContext,
+    path: &str,
+    public_key: &str,
+    filter: &FileFilter,
+) -> Result<(Manifest, Manifest, String)> {
+    let catalog = &context.catalog;
+    let mut remote_manifest = get_manifest(context, public_key).await?;
+    //a damaged local manifest only means every file is compared as new
+    let mut local_manifest = match load_manifest(path, catalog, LEGACY_LIST_NAME).await {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            let not_found = e.downcast_ref::<io::Error>().map(|x| x.kind())
+                == Some(io::ErrorKind::NotFound);
+            if !not_found {
+                println!("Ignore local manifest in {}: {}", path, e);
+            }
+            Manifest::new(catalog, Vec::new())
+        }
+    };
+    remote_manifest.files.retain(|x| filter.is_included(&x.path));
+    local_manifest.files.retain(|x| filter.is_included(&x.path));
+    let run_journal_name = String::from(path) + "/" + LEGACY_LIST_NAME + PARTIAL_SUFFIX;
+    let run_journal = fs::read_to_string(&run_journal_name)
+        .await
+        .unwrap_or_else(|_| "".to_owned());
+    Ok((remote_manifest, local_manifest, run_journal))
+}
+
+//Links and metadata of the manifest onto the files under path, return the failure count
+async fn restore_entries(path: &str, manifest: &Manifest, preserve_owner: bool) -> Result<usize> {
+    let path = path.to_string();
+    let entries = manifest.files.clone();
+    let error_count = task::spawn_blocking(move || {
+        let mut error_count = 0;
+        for entry in &entries {
+            let mut file_name = path.clone() + "/" + &entry.path;
+            //a new filer binary waits next to the old one until it is swapped in after this
+            let staged = fileutil::target_file_name(&path, &entry.path);
+            if entry.link.is_none() && staged != file_name && std::fs::metadata(&staged).is_ok() {
+                file_name = staged;
+            }
+            let result = match &entry.link {
+                Some(target) => restore_link(&path, &entry.path, target).map(|created| {
+                    if created {
+                        println!(">>Link {} -> {}", entry.path, target);
+                    }
+                }),
+                //listed as unchanged but deleted locally since, nothing to restore
+                None if std::fs::symlink_metadata(&file_name).is_err() => Ok(()),
+                None => {
+                    let meta = FileMeta {
+                        mtime: entry.mtime,
+                        mode: entry.mode,
+                        owner: entry.uid.zip(entry.gid),
+                    };
+                    restore_metadata(&file_name, meta, preserve_owner)
+                }
+            };
+            if let Err(e) = result {
+                error_count += 1;
+                println!(">>Restore {} fail: {}", entry.path, e);
+            }
+        }
+        error_count
+    })
+    .await?;
+    Ok(error_count)
 }
 
-//(digest,size,name)
-pub fn parse_file_list(str: &str) -> Vec<(&str, u64, &str)> {
-    let file_list: Vec<(&str, u64, &str)> = str
+//Lines of files verified by an interrupted run, a line torn by the interruption is skipped
+fn parse_run_journal(run_journal: &str) -> Vec<(&str, u64, &str)> {
+    run_journal
         .split(EOL)
-        .filter(|x| !x.is_empty())
-        .map(|x| {
-            let mut parts: Vec<&str> = x.split(',').collect();
-            let file_name = parts.pop().unwrap();
-            let size = parts.pop().unwrap();
-            let size = size.parse::<u64>().unwrap(); // u64::from_str_radix(&size, 10).unwrap();
-            let digest = parts.pop().unwrap();
-            (digest, size, file_name)
+        .filter_map(|line| parse_line(line).ok())
+        .collect()
+}
+
+//The manifests a run compares, what to download and prune follows from them
+struct SyncLists {
+    remote_manifest: Manifest,
+    local_manifest: Manifest,
+    //lines of files verified by an interrupted run
+    run_journal: String,
+    //mirror mode only, files never pruned when they are gone from the server
+    protected: Option<Vec<String>>,
+}
+
+impl SyncLists {
+    async fn load(
+        context: &DownloadContext,
+        client_config: &Value,
+        catalog_config: &Value,
+        mirror: bool,
+    ) -> Result<Self> {
+        let path = client_config["path"].str("./demo_sent");
+        let public_key = client_config["public_key"].str("");
+        let filter = FileFilter::load(catalog_config, path).await?;
+        let (remote_manifest, local_manifest, run_journal) =
+            read_file_lists(context, path, public_key, &filter).await?;
+        let mirror = mirror || client_config["mirror"].bool(false);
+        let protected = if mirror {
+            Some(crate::mirror::protected_files(client_config))
+        } else {
+            None
+        };
+        Ok(SyncLists {
+            remote_manifest,
+            local_manifest,
+            run_journal,
+            protected,
         })
-        .collect();
-    //file_list.sort_by_key(|x| x.0);
-    file_list
+    }
+
+    fn plan(&self, catalog: &str, download_all: bool) -> SyncPlan<'_> {
+        let orphans = match &self.protected {
+            //links count as entries here, a link gone from the server is pruned like a file
+            Some(protected) => crate::mirror::orphan_files(
+                &self.local_manifest.entry_list(),
+                &self.remote_manifest.entry_list(),
+                protected,
+            ),
+            None => Vec::new(),
+        };
+        let mut plan = sync_plan(
+            catalog,
+            download_all,
+            &self.remote_manifest.file_list(),
+            &self.local_manifest.file_list(),
+            &parse_run_journal(&self.run_journal),
+            orphans,
+        );
+        //the same chunks the download finds in its chunk store
+        let chunk_size = self.remote_manifest.header.chunk_size;
+        let on_disk: HashSet<&str> = if chunk_size > 0 && self.local_manifest.header.chunk_size == chunk_size {
+            self.local_manifest
+                .files
+                .iter()
+                .flat_map(|x| x.chunks.iter().map(|x| x.digest.as_str()))
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        let remote_chunks: HashMap<&str, &[ManifestChunk]> = self
+            .remote_manifest
+            .files
+            .iter()
+            .filter(|x| !x.chunks.is_empty())
+            .map(|x| (x.path.as_str(), x.chunks.as_slice()))
+            .collect();
+        plan.reuse_chunks(|file| remote_chunks.get(file).copied().unwrap_or(&[]), on_disk);
+        plan
+    }
 }
+
+//filer download/update --plan: print what the run would do without writing anything
+pub async fn plan_files(
+    config: &Value,
+    download_all: bool,
+    catalog: &str,
+    mirror: bool,
+    json: bool,
+) -> Result<()> {
+    let client_config = &config["client"];
+    let catalog = client_config["catalog"].string(catalog);
+    let context = DownloadContext::new(client_config, &catalog).await?;
+    let lists = SyncLists::load(&context, client_config, &config[&catalog], mirror).await?;
+    lists.plan(&catalog, download_all).print(json)?;
+    Ok(())
+}
+
 pub async fn download_files(
     config: &Value,
     download_all: bool,
     max_tasks: u64,
     catalog: &str,
+    mirror: bool,
 ) -> Result<()> {
-    use std::collections::{HashMap, HashSet};
-    use std::ffi::OsStr;
+    use crate::mirror::remove_orphan_files;
     let client_config = &config["client"];
     let kill_running = client_config["kill_running_exe"].bool(true);
     let catalog = client_config["catalog"].string(catalog);
     println!(">>catalog={catalog}");
-    let base_url = base_url(client_config);
+    let context = Arc::new(DownloadContext::new(client_config, &catalog).await?);
     let catalog_config = &config[&catalog];
     let part_size = catalog_config["part_size"].u64(1024 * 1024);
-    let (_, _, bytes) = get_full_of_file(&base_url, &catalog, "filelist.txt").await?;
-    let remote_file_list_bytes = bytes.clone();
-    let remote_file_list: String = String::from_utf8(bytes)?;
-    let remote_file_list: Vec<(&str, u64, &str)> = parse_file_list(&remote_file_list);
-    let file_count = remote_file_list.len();
-    let file_size = remote_file_list.iter().map(|x| x.1).sum::<u64>();
     let path = client_config["path"].str("./demo_sent");
     let max_tasks = client_config["max_tasks"].u64(max_tasks);
-    let local_file_list = fs::read_to_string(String::from(path) + "/filelist.txt")
-        .await
-        .unwrap_or_else(|_| "".to_owned());
-    let local_file_list: Vec<(&str, u64, &str)> = parse_file_list(&local_file_list);
-
-    //(file_name,(digest,file_size))
-    let local_file_list: HashMap<&str, (&str, u64)> = local_file_list
-        .iter()
-        .filter(|x| !x.2.is_empty())
-        .map(|x| (x.2, (x.0, x.1)))
-        .collect();
+    let lists = SyncLists::load(&context, client_config, catalog_config, mirror).await?;
+    let (remote_manifest, local_manifest) = (&lists.remote_manifest, &lists.local_manifest);
+    let remote_file_list = remote_manifest.file_list();
+    let file_count = remote_file_list.len();
+    let file_size = remote_file_list.iter().map(|x| x.1).sum::<u64>();
 
-    //filter different files (digest,file_size,file_name)
-    let remote_file_list: Vec<(&str, u64, &str)> = remote_file_list
-        .into_iter()
-        .filter(|(digest, file_size, file_name)| {
-            if file_name.to_lowercase().ends_with("filer.json")
-                || file_name.to_lowercase().ends_with("filer.exe.new")
-            {
-                false
-            } else if download_all {
-                true
-            } else {
-                local_file_list
-                    .get(file_name)
-                    .map(|(local_digest, local_size)| {
-                        !(local_digest == digest && local_size == file_size)
-                    })
-                    .unwrap_or(true)
-            }
-        })
-        .collect();
-    let download_count = remote_file_list.len();
-    let download_size = remote_file_list.iter().map(|x| x.1).sum::<u64>();
+    //files already verified by an interrupted run, filelist.txt.filer-partial
+    let run_journal_name = String::from(path) + "/" + LEGACY_LIST_NAME + PARTIAL_SUFFIX;
+    let plan = lists.plan(&catalog, download_all);
+    let download_count = plan.fetch.len() + plan.local_copies.len();
+    let download_size = plan.network_size + plan.reused_chunk_size + plan.local_copy_size;
 
-    if kill_running {
-        let exe_list = remote_file_list
+    //hooks only run around a download which changes something
+    let hooks = Hooks::from_config(catalog_config);
+    let hook_env = HookEnv {
+        catalog: &catalog,
+        path,
+        changed: plan
+            .fetch
             .iter()
-            .map(|x| Path::new(x.2))
-            .filter(|x| x.extension().unwrap_or_else(|| OsStr::new("")) == "exe")
-            .map(|x| x.file_name().unwrap().to_str().unwrap())
-            .filter(|x| x.to_lowercase() != "filer.exe")
-            .collect::<HashSet<&str>>();
-        if !exe_list.is_empty() {
-            print!("Kill running exe: ");
-            for image_name in exe_list {
-                print!("{}..", image_name);
-                match kill_running_exe(image_name).await {
-                    Ok(_) => (),
-                    Err(e) => println!("失败: {:?}", e),
-                };
-            }
-        }
-        println!();
+            .map(|x| x.file)
+            .chain(plan.local_copies.iter().map(|x| x.file))
+            .chain(plan.resumed.iter().map(|x| x.file))
+            .collect(),
+        removed: plan.removed.iter().map(|x| x.file).collect(),
+    };
+    let run_hooks = !hook_env.changed.is_empty() || !hook_env.removed.is_empty();
+    if run_hooks {
+        hooks.pre_download(&hook_env).await?;
     }
 
-    //(digest,(file_size,[file_name...]))
-    let mut unique_digest_list: HashMap<String, (u64, Vec<String>, bool)> = HashMap::new();
-    remote_file_list
-        .iter()
-        .for_each(|(digest, file_size, file_name)| {
-            if let Some(val) = unique_digest_list.get_mut(*digest) {
-                val.1.push(file_name.to_string());
-                assert_eq!(val.0, *file_size);
-            } else {
-                unique_digest_list.insert(
-                    digest.to_string(),
-                    (*file_size, vec![file_name.to_string()], false),
-                );
-            }
-        });
+    //once the pre hook ran the post hook runs too, whatever happens in between
+    let synced: Result<Option<String>> = async {
+        if kill_running {
+            let files: Vec<&str> = plan
+                .fetch
+                .iter()
+                .map(|x| x.file)
+                .chain(plan.local_copies.iter().map(|x| x.file))
+                .collect();
+            stop_running_files(path, &files).await;
+        }
 
-    use std::sync::{Arc, Mutex};
-    let unique_digest_list = Arc::new(Mutex::new(unique_digest_list));
+        let mut download_error_count: usize = 0;
+        let mut print_count: usize = 0;
 
-    //return (source_file_name,from_local)
-    let get_source_file = |file_name: &str, digest: &str| -> (String, bool) {
-        if let Ok(unique_digest_list) = unique_digest_list.lock() {
-            if let Some((_, file_list, fetched)) = unique_digest_list.get(digest) {
-                let first_file_name = file_list.get(0).unwrap().clone();
-                (first_file_name, *fetched)
-            } else {
-                (file_name.to_string(), false)
-            }
-        } else {
-            (file_name.to_string(), false)
+        if !plan.resumed.is_empty() {
+            println!("Skip {} files finished by an interrupted run", plan.resumed.len());
         }
-    };
-    let set_source_file = |digest: &str| -> bool {
-        if let Ok(mut unique_digest_list) = unique_digest_list.lock() {
-            if let Some((_, _, fetched)) = unique_digest_list.get_mut(digest) {
-                *fetched = true;
-                true
-            } else {
-                false
+        DirBuilder::new().recursive(true).create(path).await?;
+        let mut run_journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&run_journal_name)
+            .await?;
+
+        //digests fetched in this run, their duplicates are copied locally
+        let mut fetched_digests: HashSet<String> = HashSet::new();
+        let chunk_size = remote_manifest.header.chunk_size;
+        let remote_chunks: HashMap<&str, &[ManifestChunk]> = remote_manifest
+            .files
+            .iter()
+            .filter(|x| !x.chunks.is_empty())
+            .map(|x| (x.path.as_str(), x.chunks.as_slice()))
+            .collect();
+        let chunks_of = |file: &str| remote_chunks.get(file).copied().unwrap_or(&[]);
+        //chunks of the files already on disk, grown by every file this run writes
+        let mut store = ChunkStore::default();
+        if chunk_size > 0 && local_manifest.header.chunk_size == chunk_size {
+            for entry in local_manifest.files.iter().filter(|x| !x.chunks.is_empty()) {
+                store.add_file(&fileutil::target_file_name(path, &entry.path), &entry.chunks);
             }
-        } else {
-            false
         }
-    };
-
-    let mut download_error_count: usize = 0;
-    let mut i: usize = 0;
-    let mut print_count: usize = 0;
-
-    println!("Download {} ...", catalog);
-    while i < download_count {
-        let mut task_count = 0u64;
-        let mut results: Vec<(String, task::JoinHandle<Result<DowloadFileReturn>>)> =
-            Vec::with_capacity(max_tasks as usize);
-        while task_count < max_tasks && i < download_count {
-            let (digest, file_size, file_name) = *(remote_file_list
-                .get(i)
-                .ok_or_else(|| anyhow!("remote_file_list.get() error"))?);
-            let (source_file_name, from_local) = get_source_file(file_name, digest);
-            let base_url: String = base_url.clone();
-            let catalog: String = catalog.clone();
-            let path: String = path.into();
-            let digest: String = digest.into();
-            let (task_add, part_size) = calc_parts(file_size, part_size, MAX_SPLIT_PARTS);
-            let file_name_saved = file_name.into();
-            let file_name: String = file_name.into();
-            task_count += task_add;
-            let result = task::spawn(async move {
-                download_file(
-                    &base_url,
-                    &catalog,
-                    &path,
-                    &file_name,
-                    file_size,
-                    part_size,
-                    &digest,
-                    &source_file_name,
-                    from_local,
-                )
-                .await
-            });
-            results.push((file_name_saved, result));
-            i += 1;
-        }
-        for result in results {
-            print_count += 1;
-            let (file_name, result) = result;
-            let result = result.await?;
-            match result {
-                Ok((digest, file_size, parts, _part_size, from_local)) => {
-                    set_source_file(&digest);
-                    println!(
-                        ">>{: ^#4} {} {}={} pack{} ...{}",
-                        print_count,
-                        file_name,
+        let store = Arc::new(RwLock::new(store));
+        let scheduler = Scheduler::new(max_tasks);
+        let mut reused_total: u64 = 0;
+        //filer binaries land next to the running one and are swapped in after the run
+        let mut staged: Vec<(String, String)> = Vec::new();
+        println!("Download {} ...", catalog);
+        //fetch one file of each digest first, then copy the duplicates,
+        //at last give the files that failed one more try
+        //(digest,file_size,file_name,source_file_name,from_local,chunks)
+        type Job<'a> = (&'a str, u64, &'a str, &'a str, bool, &'a [ManifestChunk]);
+        let retry_failed = context.retry.retries > 0;
+        let mut failed: Vec<Job> = Vec::new();
+        for round in 0..3 {
+            let jobs: Vec<Job> = match round {
+                0 => plan
+                    .fetch
+                    .iter()
+                    .map(|x| (x.digest, x.size, x.file, x.file, false, chunks_of(x.file)))
+                    .collect(),
+                1 => plan
+                    .local_copies
+                    .iter()
+                    .map(|x| {
+                        if fetched_digests.contains(x.digest) {
+                            (x.digest, x.size, x.file, x.source, true, &[][..])
+                        } else {
+                            (x.digest, x.size, x.file, x.file, false, chunks_of(x.file))
+                        }
+                    })
+                    .collect(),
+                _ => std::mem::take(&mut failed),
+            };
+            if round == 2 && !jobs.is_empty() {
+                println!("Retry {} failed files ...", jobs.len());
+            }
+            let mut running = FuturesUnordered::new();
+            for (i, &(digest, file_size, file_name, source_file_name, from_local, chunks)) in
+                jobs.iter().enumerate()
+            {
+                let chunks = chunks.to_vec();
+                let store = store.clone();
+                let file_scheduler = scheduler.clone();
+                let context = context.clone();
+                let path: String = path.into();
+                let digest: String = digest.into();
+                let source_file_name: String = source_file_name.into();
+                let (_, part_size) = calc_parts(file_size, part_size, MAX_SPLIT_PARTS);
+                let file_name: String = file_name.into();
+                running.push(scheduler.spawn_file(async move {
+                    let result = download_file(
+                        &context,
+                        &path,
+                        &file_name,
                         file_size,
-                        parts,
-                        if parts > 1 { "s" } else { "" },
-                        if from_local { "locally copied" } else { "" }
-                    );
-                }
-                Err(e) => {
-                    download_error_count += 1;
-                    println!(">>{: ^#4} {} {:?}", print_count, file_name, e);
+                        part_size,
+                        &digest,
+                        &source_file_name,
+                        from_local,
+                        &chunks,
+                        chunk_size,
+                        &store,
+                        &file_scheduler,
+                    )
+                    .await;
+                    (i, file_name, result)
+                }));
+            }
+            while let Some(result) = running.next().await {
+                print_count += 1;
+                let (i, file_name, result) = result?;
+                match result {
+                    Ok((digest, file_size, parts, _, from_local, resumed_parts, reused_size)) => {
+                        let line = format!("{},{},{}{}", digest, file_size, file_name, EOL);
+                        //a staged binary is only done once it is installed
+                        if fileutil::is_filer_binary(path, &file_name) {
+                            staged.push((fileutil::target_file_name(path, &file_name), digest.clone()));
+                        } else {
+                            run_journal.write_all(line.as_bytes()).await?;
+                        }
+                        fetched_digests.insert(digest);
+                        reused_total += reused_size;
+                        let chunks = chunks_of(&file_name);
+                        let target_file_name = fileutil::target_file_name(path, &file_name);
+                        if let (false, Ok(mut store)) = (chunks.is_empty(), store.write()) {
+                            store.add_file(&target_file_name, chunks);
+                        }
+                        println!(
+                            ">>{: ^#4} {} {}={} pack{} ...{}",
+                            print_count,
+                            file_name,
+                            file_size,
+                            parts,
+                            if parts > 1 { "s" } else { "" },
+                            if from_local {
+                                "locally copied".to_string()
+                            } else if resumed_parts > 0 {
+                                format!("resumed {} packs", resumed_parts)
+                            } else if reused_size > 0 {
+                                format!(
+                                    "patched, reused {}",
+                                    Byte::from_bytes(reused_size as u128)
+                                        .get_appropriate_unit(false)
+                                )
+                            } else {
+                                "".to_string()
+                            }
+                        );
+                    }
+                    Err(e) if round < 2 && retry_failed => {
+                        failed.push(jobs[i]);
+                        println!(">>{: ^#4} {} {:?}, retry later", print_count, file_name, e);
+                    }
+                    Err(e) => {
+                        download_error_count += 1;
+                        println!(">>{: ^#4} {} {:?}", print_count, file_name, e);
+                    }
                 }
             }
         }
-    }
 
-    if download_count - download_error_count > 0 {
-        println!("Write filelist.txt which content from server");
-        let file_name = String::from(path) + "/filelist.txt";
-        let mut file = File::create(&file_name).await?;
-        file.write_all(&remote_file_list_bytes).await?;
-    }
-    println!(
-        "Total {} files with size {}, download {} files with size {} with failure count {}.",
-        file_count,
-        Byte::from_bytes(file_size as u128).get_appropriate_unit(false),
-        download_count,
-        Byte::from_bytes(download_size as u128).get_appropriate_unit(false),
-        download_error_count
-    );
-    if download_count > 0 {
+        run_journal.flush().await?;
+        drop(run_journal);
+        if download_error_count == 0 {
+            let preserve_owner = client_config["preserve_owner"].bool(false);
+            download_error_count += restore_entries(path, remote_manifest, preserve_owner).await?;
+        }
+        let mut restart_binary = None;
+        if download_error_count == 0 && !staged.is_empty() {
+            let (error_count, binary) = install_binaries(&staged).await;
+            download_error_count += error_count;
+            restart_binary = binary;
+        }
+        //keep the run journal until every file is in place, so a rerun resumes instead of starting over
+        if download_error_count == 0 {
+            println!("Write {} and {} which content from server", MANIFEST_NAME, LEGACY_LIST_NAME);
+            let file_name = String::from(path) + "/" + MANIFEST_NAME;
+            write_file_atomic(&file_name, &remote_manifest.to_json()?).await?;
+            let file_name = String::from(path) + "/" + LEGACY_LIST_NAME;
+            write_file_atomic(&file_name, remote_manifest.to_legacy().as_bytes()).await?;
+            let _ = fs::remove_file(&run_journal_name).await;
+            if !plan.removed.is_empty() {
+                let orphans: Vec<(&str, u64)> = plan.removed.iter().map(|x| (x.file, x.size)).collect();
+                let (pruned_count, pruned_size) =
+                    remove_orphan_files(client_config, path, &orphans, false).await?;
+                println!(
+                    "Prune {} files with size {} removed from the server",
+                    pruned_count,
+                    Byte::from_bytes(pruned_size as u128).get_appropriate_unit(false)
+                );
+            }
+        } else {
+            println!("Keep {} to resume the failed files", run_journal_name);
+        }
         println!(
-            "Max concurrent {} tasks, each pack size {}",
-            max_tasks,
-            Byte::from_bytes(part_size as u128).get_appropriate_unit(true)
+            "Total {} files with size {}, download {} files with size {} with failure count {}.",
+            file_count,
+            Byte::from_bytes(file_size as u128).get_appropriate_unit(false),
+            download_count,
+            Byte::from_bytes(download_size as u128).get_appropriate_unit(false),
+            download_error_count
         );
+        if reused_total > 0 {
+            println!(
+                "Chunks already on disk saved downloading {}",
+                Byte::from_bytes(reused_total as u128).get_appropriate_unit(false)
+            );
+        }
+        if download_count > 0 {
+            println!(
+                "Max concurrent {} tasks, each pack size {}",
+                max_tasks,
+                Byte::from_bytes(part_size as u128).get_appropriate_unit(true)
+            );
+        }
+        //a non-zero exit status tells scripts the copy is incomplete
+        if download_error_count > 0 {
+            return Err(anyhow!("{} files failed to download", download_error_count));
+        }
+        Ok(restart_binary)
+    }
+    .await;
+    if run_hooks {
+        let hooked = hooks.post_download(&hook_env, synced.is_ok()).await;
+        match &synced {
+            Ok(_) => hooked?,
+            Err(_) => {
+                if let Err(e) = hooked {
+                    println!("{:?}", e);
+                }
+            }
+        }
+    }
+    if let Some(binary) = synced? {
+        restart(&binary)?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileutil::TempPath;
+
+    #[tokio::test]
+    async fn test_part_journal() {
+        let journal_file = TempPath::new(&(String::from("journal") + PARTIAL_SUFFIX));
+        let journal_name = journal_file.str();
+        let mut journal = PartJournal::new("abc", 300, 100);
+        journal.parts.insert(1, "p1".to_string());
+        journal.save(journal_name).await.unwrap();
+
+        let loaded = PartJournal::load(journal_name, "abc", 300, 100).await;
+        assert_eq!(loaded.parts.get(&1).map(|x| x.as_str()), Some("p1"));
+        //another version of the remote file or another part layout starts over
+        assert!(PartJournal::load(journal_name, "def", 300, 100).await.parts.is_empty());
+        assert!(PartJournal::load(journal_name, "abc", 300, 50).await.parts.is_empty());
+
+        fs::write(journal_name, "{torn").await.unwrap();
+        assert!(PartJournal::load(journal_name, "abc", 300, 100).await.parts.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_download_keeps_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp = TempPath::new("keep-mode");
+        let dir = temp.path();
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("app.sh"), "echo 1").unwrap();
+        std::fs::set_permissions(dir.join("app.sh"), std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        std::fs::write(dir.join("new.sh"), "echo 2").unwrap();
+        let digest = format!("{}", blake3::hash(b"echo 2").to_hex());
+
+        //a legacy manifest has no mode, the old file keeps its own
+        let context = Arc::new(DownloadContext::new(&json!({}), "demo").await.unwrap());
+        download_file(
+            &context,
+            dir.to_str().unwrap(),
+            "app.sh",
+            6,
+            1024,
+            &digest,
+            "new.sh",
+            true,
+            &[],
+            0,
+            &RwLock::new(ChunkStore::default()),
+            &Scheduler::new(4),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("app.sh")).unwrap(), "echo 2");
+        let mode = std::fs::metadata(dir.join("app.sh")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let retry = RetryPolicy::from_config(&json!({"retry_delay": 1000, "retry_max_delay": 5000}));
+        assert_eq!(retry.retries, 4);
+        let millis = |attempt, random| retry.backoff(attempt, random).as_millis();
+        assert_eq!((millis(0, 0), millis(0, 500)), (500, 1000));
+        assert_eq!((millis(1, 0), millis(1, 1000)), (1000, 2000));
+        assert_eq!((millis(2, 0), millis(2, 2000)), (2000, 4000));
+        //capped by retry_max_delay, even after many attempts
+        assert_eq!((millis(3, 0), millis(3, 2500)), (2500, 5000));
+        assert_eq!((millis(60, 0), millis(60, 2500)), (2500, 5000));
+        for _ in 0..100 {
+            assert!((1000..=2000).contains(&millis(1, random())));
+        }
+    }
+}
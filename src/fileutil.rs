@@ -1,12 +1,31 @@
 #![allow(dead_code)]
 
 use anyhow::{anyhow, Result};
-use byte_unit::Byte;
 use std::io::SeekFrom;
+use std::path::PathBuf;
 use tokio::fs::{self, File};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::task;
+#[cfg(any(feature = "digest", feature = "download", feature = "xcopy"))]
 use futures_util::{future::BoxFuture, FutureExt};
+use once_cell::sync::Lazy;
+#[cfg(any(feature = "digest", feature = "download", feature = "xcopy"))]
+use crate::filter::FileFilter;
+#[cfg(feature = "digest")]
+use byte_unit::Byte;
+#[cfg(feature = "digest")]
+use futures_util::stream::{FuturesUnordered, StreamExt};
+#[cfg(feature = "digest")]
+use crate::scheduler::Scheduler;
+
+#[cfg(feature = "digest")]
+use crate::index_cache::{FileStamp, IndexCache, CACHE_SUFFIX};
+#[cfg(feature = "digest")]
+use crate::chunking::{chunk_file, should_chunk};
+#[cfg(feature = "digest")]
+use crate::manifest::{Manifest, ManifestChunk, ManifestEntry, MANIFEST_NAME};
+#[cfg(feature = "digest")]
+use crate::signing::{load_signing_key, public_key, sign, SIGNATURE_SUFFIX};
 
 pub type PartData = (u64, u64, Vec<u8>);
 pub const EOL: &str = "\r\n";
@@ -46,24 +65,117 @@ pub async fn get_part_of_file(file_name: &str, skip: u64, take: u64) -> Result<P
     }
 }
 
+//Open file_name for streaming, return (skip,take,reader) with take clamped to the file end, take == 0 means to the end
+pub async fn open_part_of_file(
+    file_name: &str,
+    skip: u64,
+    take: u64,
+) -> Result<(u64, u64, tokio::io::Take<File>)> {
+    let mut file = File::open(file_name).await?;
+    let file_size = file.metadata().await?.len();
+    let skip = skip.min(file_size);
+    let rest = file_size - skip;
+    let take = if take == 0 { rest } else { take.min(rest) };
+    if skip > 0 {
+        file.seek(SeekFrom::Start(skip)).await?;
+    }
+    Ok((skip, take, file.take(take)))
+}
+
+//Files and symlinks found under a path, links as (file_name,target)
+#[cfg(any(feature = "digest", feature = "download", feature = "xcopy"))]
+#[derive(Default)]
+pub struct DirEntries {
+    pub files: Vec<String>,
+    pub links: Vec<(String, String)>,
+}
+
+//Files under path the filter lets through, excluded directories are not walked at all.
+//Symlinks are listed as links, with follow_links they are walked like what they point to,
+//a link back into a directory being walked is skipped instead of looping forever
+//and so is one leading outside of path
+#[cfg(any(feature = "digest", feature = "download", feature = "xcopy"))]
+pub async fn get_dir_entries(
+    path: &str,
+    filter: &FileFilter,
+    follow_links: bool,
+) -> Result<DirEntries> {
+    let mut results = DirEntries::default();
+    let ancestors = if follow_links {
+        vec![fs::canonicalize(path).await?]
+    } else {
+        Vec::new()
+    };
+    walk_dir(path, path.len() + 1, filter, follow_links, ancestors, &mut results).await?;
+    Ok(results)
+}
+
 //Async recursive version  
-pub fn get_dir_file_names(path: &str) -> BoxFuture<Result<Vec<String>>> {
+#[cfg(any(feature = "digest", feature = "download", feature = "xcopy"))]
+fn walk_dir<'a>(
+    path: &'a str,
+    root_len: usize,
+    filter: &'a FileFilter,
+    follow_links: bool,
+    ancestors: Vec<PathBuf>,
+    results: &'a mut DirEntries,
+) -> BoxFuture<'a, Result<()>> {
     async move {
-        let mut results: Vec<String> = Vec::new();
         let mut entries = tokio::fs::read_dir(&path).await?;
         while let Some(entry) = entries.next_entry().await? {
+            //the entry itself, a symlink is not followed here
             let meta = entry.metadata().await?;
             let file_name = entry.file_name();
             let file_name: String = file_name.to_str().unwrap().into();
             let full_name = String::from(path) + "/" + &file_name;
-            if meta.is_dir() {
-                let mut files = get_dir_file_names(&full_name).await?;
-                results.append(&mut files);
-            } else {
-                results.push(full_name);
+            let relative = &full_name[root_len..];
+            let mut is_dir = meta.is_dir();
+            if meta.file_type().is_symlink() {
+                //a dangling link is kept as a link even when links are followed
+                match fs::metadata(&full_name).await {
+                    Ok(target) if follow_links => {
+                        //the server only serves what is inside the catalog
+                        let real_path = fs::canonicalize(&full_name).await?;
+                        if !real_path.starts_with(&ancestors[0]) {
+                            println!("Skip symlink {} -> {} outside of the catalog", full_name, real_path.display());
+                            continue;
+                        }
+                        is_dir = target.is_dir();
+                    }
+                    _ => {
+                        if filter.is_included(relative) {
+                            let target = fs::read_link(&full_name).await?;
+                            let target = target.to_string_lossy().replace('\\', "/");
+                            //clients refuse links out of the catalog
+                            if is_link_inside(relative, &target) {
+                                results.links.push((full_name, target));
+                            } else {
+                                println!("Skip symlink {} -> {} outside of {}", full_name, target, &path[..root_len - 1]);
+                            }
+                        }
+                        continue;
+                    }
+                }
+            }
+            if is_dir {
+                if !filter.is_dir_included(relative) {
+                    continue;
+                }
+                let mut ancestors = ancestors.clone();
+                if follow_links {
+                    let real_path = fs::canonicalize(&full_name).await?;
+                    if ancestors.contains(&real_path) {
+                        println!("Skip symlink cycle {} -> {}", full_name, real_path.display());
+                        continue;
+                    }
+                    ancestors.push(real_path);
+                }
+                walk_dir(&full_name, root_len, filter, follow_links, ancestors, results).await?;
+            } else if filter.is_included(relative) {
+                results.files.push(full_name);
             }
         }
-        Ok(results)
+        Ok(())
     }.boxed()
 }
 /*
@@ -88,69 +200,92 @@ pub fn get_dir_file_names(path: &str) -> Result<Vec<String>> {
 */
 
 #[cfg(feature = "digest")]
-//return [(file_name,size,digest)...]
+type HashedFile = (String, u64, String, FileStamp, Vec<ManifestChunk>);
+
+#[cfg(feature = "digest")]
+//return [(file_name,size,digest,stamp,chunks)...], chunks only for files worth chunking
 pub async fn get_dir_file_size_and_digest(
     path: &str,
     part_size: u64,
+    chunk_size: u64,
     max_tasks: u64,
     show_progress: bool,
-) -> Result<Vec<(String, u64, String)>> {
+    cache: &mut IndexCache,
+    files: Vec<String>,
+) -> Result<Vec<HashedFile>> {
     let path = String::from(path);
-    let files = get_dir_file_names(&path).await?;
     let file_count = files.len();
-    let mut results: Vec<(String, u64, String)> = Vec::with_capacity(file_count);
+    //(position in the listing,file), the manifest keeps the listing order
+    let mut results: Vec<(usize, HashedFile)> = Vec::with_capacity(file_count);
     let mut calc_error_count: usize = 0;
+    let mut cached_count: usize = 0;
     let mut print_count: u64 = 0;
-    let mut i: usize = 0;
-
-    while i < file_count {
-        let mut task_count = 0u64;
-        let mut tasks: Vec<task::JoinHandle<Result<(String, u64, String)>>> =
-            Vec::with_capacity(file_count);
-        while task_count < max_tasks && i < file_count {
-            let file = files.get(i).ok_or_else(||anyhow!("digest files.get() error"))?;
-            let file_name = file.clone();
-            let task = task::spawn(async move {
-                get_file_size_and_digest(&file_name, part_size, max_tasks)
-                    .await
-                    .map(|(size, digest)| (file_name, size, digest))
-            });
-            tasks.push(task);
-            task_count += 1;
-            i += 1;
+    let scheduler = Scheduler::new(max_tasks);
+    let mut running = FuturesUnordered::new();
+    for (i, file_name) in files.into_iter().enumerate() {
+        //unchanged since the last run, reuse its digest
+        let stamp = fs::metadata(&file_name).await.map(|x| FileStamp::new(&x));
+        if let Ok(stamp) = stamp {
+            if let Some((digest, chunks)) = cache.lookup(&file_name[path.len() + 1..], &stamp) {
+                cached_count += 1;
+                results.push((i, (file_name, stamp.size, digest, stamp, chunks)));
+                continue;
+            }
         }
-        for task in tasks {
-            let result = task.await?;
-            print_count += 1;
-            match result {
-                Ok((file_name, file_size, digest)) => {
-                    if show_progress {
-                        println!(
-                            ">>{: ^#4} {} {} ...",
-                            print_count,
-                            &file_name,
-                            Byte::from_bytes(file_size as u128).get_appropriate_unit(false)
-                        );
-                    }
-                    results.push((file_name, file_size, digest));
+        let file_scheduler = scheduler.clone();
+        running.push(scheduler.spawn_file(async move {
+            let result: Result<HashedFile> = async {
+                let stamp = stamp?;
+                if should_chunk(stamp.size, chunk_size) {
+                    let (digest, chunks) =
+                        file_scheduler.run(chunk_file(&file_name, chunk_size)).await?;
+                    let size = chunks.iter().map(|x| x.size).sum();
+                    return Ok((file_name, size, digest, stamp, chunks));
                 }
-                Err(e) => {
-                    calc_error_count += 1;
-                    if show_progress {
-                        println!(">>{: ^#4} {:?}", print_count, e);
-                    }
+                get_file_size_and_digest(&file_name, part_size, max_tasks, &file_scheduler)
+                    .await
+                    .map(|(size, digest)| (file_name, size, digest, stamp, Vec::new()))
+            }
+            .await;
+            (i, result)
+        }));
+    }
+    while let Some(result) = running.next().await {
+        let (i, result) = result?;
+        print_count += 1;
+        match result {
+            Ok((file_name, file_size, digest, stamp, chunks)) => {
+                cache.insert(&file_name[path.len() + 1..], stamp, &digest, &chunks);
+                if show_progress {
+                    println!(
+                        ">>{: ^#4} {} {} ...",
+                        print_count,
+                        &file_name,
+                        Byte::from_bytes(file_size as u128).get_appropriate_unit(false)
+                    );
+                }
+                results.push((i, (file_name, file_size, digest, stamp, chunks)));
+            }
+            Err(e) => {
+                calc_error_count += 1;
+                if show_progress {
+                    println!(">>{: ^#4} {:?}", print_count, e);
                 }
             }
         }
     }
+    if show_progress && cached_count > 0 {
+        println!("Skip hashing {} files unchanged since the last index", cached_count);
+    }
     if show_progress && calc_error_count > 0 {
         println!("Total digest calc error count {}", calc_error_count);
     }
-    Ok(results)
+    results.sort_by_key(|x| x.0);
+    Ok(results.into_iter().map(|x| x.1).collect())
 }
 
 pub fn calc_parts(file_size: u64, part_size: u64, max_split_parts: u64) -> (u64, u64) {
-    let parts = (file_size + part_size - 1) / part_size;
+    let parts = file_size.div_ceil(part_size);
     if parts <= max_split_parts {
         (parts, part_size)
     } else {
@@ -159,9 +294,7 @@ pub fn calc_parts(file_size: u64, part_size: u64, max_split_parts: u64) -> (u64,
 }
 
 //return [(file_name,size)...]
-pub async fn get_dir_file_size(path: &str) -> Result<Vec<(String, u64)>> {
-    let path = String::from(path);
-    let files = get_dir_file_names(&path).await?;
+pub async fn get_files_size(files: Vec<String>) -> Result<Vec<(String, u64)>> {
     let file_count = files.len();
     let mut results: Vec<(String, u64)> = Vec::with_capacity(file_count);
     let mut tasks: Vec<task::JoinHandle<Result<(String, u64)>>> = Vec::with_capacity(file_count);
@@ -186,6 +319,7 @@ pub async fn get_file_size_and_digest(
     file_name: &str,
     part_size: u64,
     max_tasks: u64,
+    scheduler: &Scheduler,
 ) -> Result<(u64, String)> {
     use blake3::Hasher;
     let file_size = get_file_size(file_name).await?;
@@ -198,14 +332,14 @@ pub async fn get_file_size_and_digest(
     let mut digest = Hasher::new();
     let mut results: Vec<task::JoinHandle<Result<PartData>>> = Vec::with_capacity(parts as usize);
     if parts == 1 {
-        let (_, _, part) = get_full_of_file(file_name).await?;
+        let (_, _, part) = scheduler.run(get_full_of_file(file_name)).await?;
         digest.update(&part);
     } else {
         for i in 0..parts as usize {
             let skip = i as u64 * part_size;
             let take = part_size;
             let file_name: String = file_name.into();
-            let result = task::spawn(async move { get_part_of_file(&file_name, skip, take).await });
+            let result = scheduler.spawn(async move { get_part_of_file(&file_name, skip, take).await });
             results.push(result);
         }
         for result in results {
@@ -267,18 +401,123 @@ pub async fn get_file_size_and_digest(
 // }
 
 pub async fn write_string_to_file(str: &str, file_name: &str) -> Result<bool> {
-    let mut target = File::create(file_name).await?;
-    let bytes: Vec<u8> = str.as_bytes().into();
-    target.write_all(&bytes).await?;
+    write_file_atomic(file_name, str.as_bytes()).await?;
     Ok(true)
 }
+
+//Suffix of the file a copy is written to before it replaces the target
+pub const TEMP_SUFFIX: &str = ".filer-tmp";
+
+//The running filer.exe can not be overwritten, it is saved as filer.exe.new instead
+//A new filer binary is written next to the old one as <name>.new and swapped in at the end of the run
+pub const NEW_BINARY_SUFFIX: &str = ".new";
+
+//filer.exe on Windows, filer elsewhere, at the root of a catalog
+pub fn is_catalog_binary(file_name: &str) -> bool {
+    let file_name = file_name.to_lowercase();
+    file_name == "filer" || file_name == "filer.exe"
+}
+
+//The running filer itself, which may be started from anywhere in a catalog
+pub fn is_running_binary(file_name: &str) -> bool {
+    static CURRENT_EXE: Lazy<Option<PathBuf>> =
+        Lazy::new(|| std::env::current_exe().and_then(std::fs::canonicalize).ok());
+    let current = match CURRENT_EXE.as_ref() {
+        Some(current) => current,
+        None => return false,
+    };
+    //only a file of the same name is worth resolving
+    let base_name = |x: &std::path::Path| x.file_name().map(|x| x.to_ascii_lowercase());
+    let same_name = base_name(std::path::Path::new(file_name)) == base_name(current);
+    same_name && std::fs::canonicalize(file_name).is_ok_and(|x| &x == current)
+}
+
+//A file of the catalog in path which is installed as the filer binary rather than written over
+pub fn is_filer_binary(path: &str, file_name: &str) -> bool {
+    is_catalog_binary(file_name) || is_running_binary(&(String::from(path) + "/" + file_name))
+}
+
+pub fn target_file_name(path: &str, file_name: &str) -> String {
+    if is_filer_binary(path, file_name) {
+        String::from(path) + "/" + file_name + NEW_BINARY_SUFFIX
+    } else {
+        String::from(path) + "/" + file_name
+    }
+}
+
+//Whether a link at the catalog relative path link with this target stays inside the catalog,
+//absolute targets are refused and ".." may not climb above the root
+pub fn is_link_inside(link: &str, target: &str) -> bool {
+    let is_absolute = target.starts_with(['/', '\\'])
+        || target.as_bytes().get(1) == Some(&b':')
+        || std::path::Path::new(target).has_root();
+    if target.is_empty() || is_absolute {
+        return false;
+    }
+    let mut depth = link.split('/').count() as i64 - 1;
+    for name in target.split(['/', '\\']) {
+        match name {
+            "" | "." => {}
+            ".." => depth -= 1,
+            _ => depth += 1,
+        }
+        if depth < 0 {
+            return false;
+        }
+    }
+    true
+}
+
+//Refuse to write file_name when its folder resolves outside of root,
+//through a symlink in the way, before anything is created there
+pub fn check_inside(root: &str, file_name: &str) -> Result<()> {
+    let outside = || anyhow!("{} leads outside of {}", file_name, root);
+    let root = std::fs::canonicalize(root)?;
+    let mut folder = std::path::Path::new(file_name).parent();
+    while let Some(dir) = folder {
+        //the nearest folder which exists decides, a dangling link is refused
+        if std::fs::symlink_metadata(dir).is_ok() {
+            return match std::fs::canonicalize(dir) {
+                Ok(dir) if dir.starts_with(&root) => Ok(()),
+                _ => Err(outside()),
+            };
+        }
+        folder = dir.parent();
+    }
+    Err(outside())
+}
+
+//Flush temp to disk then move it over target_file_name, the old target stays intact until then
+pub async fn replace_with_temp(
+    mut temp: File,
+    temp_file_name: &str,
+    target_file_name: &str,
+) -> Result<()> {
+    temp.flush().await?;
+    temp.sync_all().await?;
+    drop(temp);
+    //a replaced file keeps its permissions, like one written in place,
+    //a mode from the manifest or the copied source is applied afterwards
+    if let Ok(meta) = fs::metadata(target_file_name).await {
+        fs::set_permissions(temp_file_name, meta.permissions()).await?;
+    }
+    fs::rename(temp_file_name, target_file_name).await?;
+    Ok(())
+}
+
+pub async fn write_file_atomic(file_name: &str, bytes: &[u8]) -> Result<()> {
+    let temp_file_name = String::from(file_name) + TEMP_SUFFIX;
+    let mut temp = File::create(&temp_file_name).await?;
+    temp.write_all(bytes).await?;
+    replace_with_temp(temp, &temp_file_name, file_name).await
+}
 pub async fn get_file(
     source_file_name: &str,
     source_file_size: u64,
     part_size: u64,
     target_file_name: &str,
 ) -> Result<u64> {
-    let parts = (source_file_size + part_size - 1) / part_size;
+    let parts = source_file_size.div_ceil(part_size);
     let source_file_name = String::from(source_file_name);
     println!(
         "get {} save as {} with size {},splited {} parts*{} ...",
@@ -327,7 +566,7 @@ pub async fn get_file_and_digest(
     target_file_name: &str,
 ) -> Result<(u64, String)> {
     use blake3::Hasher;
-    let parts = (source_file_size + part_size - 1) / part_size;
+    let parts = source_file_size.div_ceil(part_size);
     let source_file_name = String::from(source_file_name);
     println!(
         "get {} save as {} with size {},splited {} parts*{} ...",
@@ -376,47 +615,135 @@ pub async fn get_file_and_digest(
     Ok((target_file_size, digest))
 }
 
+//Every file of the catalog path is published, the secret key must live elsewhere
+#[cfg(feature = "digest")]
+async fn load_publisher_key(path: &str, key_file: &str) -> Result<ed25519_dalek::SigningKey> {
+    let key_path = fs::canonicalize(key_file)
+        .await
+        .map_err(|e| anyhow!("signing key {} fail: {}", key_file, e))?;
+    if key_path.starts_with(fs::canonicalize(path).await?) {
+        return Err(anyhow!("signing key {} must not be inside {}", key_file, path));
+    }
+    load_signing_key(key_file).await
+}
+
 #[cfg(feature = "digest")]
+#[allow(clippy::too_many_arguments)]
 pub async fn refresh_dir_files_digest(
+    catalog: &str,
     path: &str,
     list_file_name: &str,
+    signing_key: &str,
     part_size: u64,
+    chunk_size: u64,
     max_tasks: u64,
     show_repeat: bool,
+    full: bool,
+    filter: &FileFilter,
+    follow_links: bool,
 ) -> Result<()> {
     use std::collections::HashMap;
     let path_len = path.len();
+    //load the key first, a missing key must not leave an unsigned manifest behind
+    let signing_key = if signing_key.is_empty() {
+        None
+    } else {
+        Some(load_publisher_key(path, signing_key).await?)
+    };
     println!("Calc digest for files in {}...", path);
-    //(file_name,file_size,digest)
-    let results = get_dir_file_size_and_digest(path, part_size, max_tasks, true).await?;
+    let cache_file_name = String::from(path) + "/" + list_file_name + CACHE_SUFFIX;
+    let mut cache = if full {
+        IndexCache::empty(chunk_size)
+    } else {
+        IndexCache::load(&cache_file_name, chunk_size).await
+    };
+    let entries = get_dir_entries(path, filter, follow_links).await?;
+    //(file_name,file_size,digest,stamp,chunks)
+    let results = get_dir_file_size_and_digest(
+        path,
+        part_size,
+        chunk_size,
+        max_tasks,
+        true,
+        &mut cache,
+        entries.files,
+    )
+    .await?;
+    let manifest_file_name = String::from(path) + "/" + MANIFEST_NAME;
+    let signature_file_name = manifest_file_name.clone() + SIGNATURE_SUFFIX;
     let list_file_name = path.to_lowercase() + "/" + list_file_name;
+    //the lists and their sidecars are never listed themselves
+    let skipped_names = [
+        list_file_name.clone(),
+        cache_file_name.to_lowercase(),
+        manifest_file_name.to_lowercase(),
+        signature_file_name.to_lowercase(),
+    ];
     let file_list_iter = results
         .iter()
-        .filter(|x| x.0.to_lowercase() != list_file_name);
+        .filter(|x| !skipped_names.contains(&x.0.to_lowercase()));
     let total_size = file_list_iter
         .clone()
-        .fold(0, |sum, (_, file_size, _)| sum + file_size);
+        .fold(0, |sum, (_, file_size, _, _, _)| sum + file_size);
     let total_size_with_unit = Byte::from_bytes(total_size as u128).get_appropriate_unit(false);
-    let file_list_joined = file_list_iter
+    let links = entries
+        .links
+        .iter()
+        .map(|(file_name, target)| ManifestEntry {
+            path: file_name.get(path_len + 1..).unwrap().to_string(),
+            size: 0,
+            digest: format!("{}", blake3::hash(target.as_bytes()).to_hex()),
+            mtime: None,
+            mode: None,
+            uid: None,
+            gid: None,
+            link: Some(target.clone()),
+            chunks: Vec::new(),
+        });
+    let files = file_list_iter
         .clone()
-        .map(|x| {
-            let file_name = x.0.get(path_len + 1..).unwrap();
-            format!("{},{},{}", x.2, x.1, file_name)
+        .map(|(file_name, size, digest, stamp, chunks)| ManifestEntry {
+            path: file_name.get(path_len + 1..).unwrap().to_string(),
+            size: *size,
+            digest: digest.clone(),
+            mtime: Some(stamp.mtime_secs),
+            mode: if cfg!(unix) { Some(stamp.mode) } else { None },
+            uid: if cfg!(unix) { Some(stamp.uid) } else { None },
+            gid: if cfg!(unix) { Some(stamp.gid) } else { None },
+            link: None,
+            chunks: chunks.clone(),
         })
-        .fold("".to_string(), |joined, x| {
-            let sep = if joined.is_empty() { "" } else { EOL };
-            joined + sep + &x
-        });
-    write_string_to_file(&file_list_joined, &list_file_name).await?;
+        .chain(links)
+        .collect();
+    let mut manifest = Manifest::new(catalog, files);
+    if manifest.files.iter().any(|x| !x.chunks.is_empty()) {
+        manifest.header.chunk_size = chunk_size;
+    }
+    write_string_to_file(&manifest.to_legacy(), &list_file_name).await?;
+    let manifest_bytes = manifest.to_json()?;
+    write_file_atomic(&manifest_file_name, &manifest_bytes).await?;
+    match signing_key {
+        Some(key) => {
+            let signature = sign(&key, &manifest_bytes);
+            write_file_atomic(&signature_file_name, signature.as_bytes()).await?;
+            println!("Manifest signed by public key {}", public_key(&key));
+        }
+        //a stale signature would only make pinned clients reject the new manifest
+        None => {
+            let _ = fs::remove_file(&signature_file_name).await;
+        }
+    }
+    cache.save(&cache_file_name).await?;
     println!(
-        "\nTotal {} files with size {},digest checksum write to {}",
-        results.len(),
+        "\nTotal {} files with size {},digest checksum write to {} and {}",
+        file_list_iter.clone().count(),
         total_size_with_unit,
-        &list_file_name
+        &list_file_name,
+        &manifest_file_name
     );
     if show_repeat {
         let mut unique_digest_list: HashMap<String, (u64, Vec<String>)> = HashMap::new();
-        file_list_iter.for_each(|(file_name, file_size, digest)| {
+        file_list_iter.for_each(|(file_name, file_size, digest, _, _)| {
             if let Some(val) = unique_digest_list.get_mut(digest) {
                 val.1.push(file_name.clone());
                 assert_eq!(val.0, *file_size);
@@ -474,31 +801,123 @@ pub async fn refresh_dir_files_digest(
     Ok(())
 }
 
-pub async fn kill_running_exe(image_name: &str) -> Result<(i32, String)> {
-    use std::process::Stdio;
-    use tokio::process::Command;
-    let mut child = Command::new("taskkill")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .arg("/IM")
-        .arg(image_name)
-        .arg("/T")
-        .arg("/F")
-        .spawn()?;
-
-    // Await until the command completes
-    // use std::process::Output;
-    // let Output {
-    //     status,
-    //     stdout,
-    //     stderr,
-    // } = child.wait_with_output().await?;
-    // let out = String::from_utf8(stdout)?;
-    // let err = String::from_utf8(stderr)?;
-    // println!("stdout:{},stderr:{}", out, err);
-    let status = child.wait().await?;
-    let exit_code = status
-        .code()
-        .ok_or_else(||anyhow!("kill_running_exe get exit_code fail"))?;
-    Ok((exit_code, String::from(image_name)))
+//A file or folder in the temp dir for a test, removed when dropped even if an assert fails
+#[cfg(test)]
+pub struct TempPath(PathBuf);
+
+#[cfg(test)]
+impl TempPath {
+    //filer-<pid>-<name>, so test runs at the same time never share it
+    pub fn new(name: &str) -> Self {
+        TempPath(std::env::temp_dir().join(format!("filer-{}-{}", std::process::id(), name)))
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+
+    pub fn str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        match std::fs::symlink_metadata(&self.0) {
+            Ok(meta) if meta.is_dir() => {
+                let _ = std::fs::remove_dir_all(&self.0);
+            }
+            Ok(_) => {
+                let _ = std::fs::remove_file(&self.0);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn temp_file(name: &str, content: &[u8]) -> TempPath {
+        let file = TempPath::new(name);
+        fs::write(file.path(), content).await.unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn test_open_part_of_file() {
+        let file = temp_file("open_part", b"0123456789").await;
+        let read = |skip, take| {
+            let file_name = file.str().to_string();
+            async move {
+                let (skip, take, mut reader) = open_part_of_file(&file_name, skip, take).await.unwrap();
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes).await.unwrap();
+                (skip, take, String::from_utf8(bytes).unwrap())
+            }
+        };
+        assert_eq!(read(0, 0).await, (0, 10, "0123456789".to_string()));
+        assert_eq!(read(2, 3).await, (2, 3, "234".to_string()));
+        assert_eq!(read(8, 5).await, (8, 2, "89".to_string()));
+        assert_eq!(read(12, 5).await, (10, 0, "".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_write_file_atomic() {
+        let file = temp_file("atomic", b"old").await;
+        let file_name = file.str();
+        write_file_atomic(file_name, b"new").await.unwrap();
+        assert_eq!(fs::read(file_name).await.unwrap(), b"new");
+        assert!(fs::metadata(String::from(file_name) + TEMP_SUFFIX).await.is_err());
+    }
+
+    #[cfg(all(unix, any(feature = "digest", feature = "download", feature = "xcopy")))]
+    #[tokio::test]
+    async fn test_get_dir_entries_links() {
+        use std::os::unix::fs::symlink;
+        let dir = TempPath::new("walk");
+        let outside = dir.path().join("outside");
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("sub")).await.unwrap();
+        fs::create_dir_all(&outside).await.unwrap();
+        fs::write(root.join("sub/a.txt"), "a").await.unwrap();
+        fs::write(outside.join("b.txt"), "b").await.unwrap();
+        symlink("..", root.join("sub/loop")).unwrap();
+        symlink("sub", root.join("current")).unwrap();
+        symlink(&outside, root.join("abs")).unwrap();
+        symlink("../outside", root.join("up")).unwrap();
+        let path = root.to_str().unwrap();
+        let names = |x: Vec<String>| {
+            let mut x: Vec<String> = x.into_iter().map(|x| x[path.len() + 1..].to_string()).collect();
+            x.sort();
+            x
+        };
+
+        //links out of the catalog are neither listed nor followed
+        let entries = get_dir_entries(path, &FileFilter::default(), false).await.unwrap();
+        assert_eq!(names(entries.files), vec!["sub/a.txt"]);
+        let links: Vec<String> = entries.links.into_iter().map(|x| x.0).collect();
+        assert_eq!(names(links), vec!["current", "sub/loop"]);
+        let entries = get_dir_entries(path, &FileFilter::default(), true).await.unwrap();
+        assert_eq!(names(entries.files), vec!["current/a.txt", "sub/a.txt"]);
+        assert!(entries.links.is_empty());
+    }
+
+    #[test]
+    fn test_target_file_name() {
+        assert_eq!(target_file_name("./a", "b/c.txt"), "./a/b/c.txt");
+        assert_eq!(target_file_name("./a", "Filer.exe"), "./a/Filer.exe.new");
+        assert_eq!(target_file_name("./a", "filer"), "./a/filer.new");
+        //only the root binary or the running one, not any file of that name
+        assert_eq!(target_file_name("./a", "docs/filer"), "./a/docs/filer");
+        assert_eq!(target_file_name("./a", "myfiler.exe"), "./a/myfiler.exe");
+        let current = std::env::current_exe().unwrap();
+        let folder = current.parent().unwrap().to_str().unwrap();
+        let name = current.file_name().unwrap().to_str().unwrap();
+        assert!(is_filer_binary(folder, name));
+        assert_eq!(target_file_name("./a", "filer.json"), "./a/filer.json");
+        assert_eq!(target_file_name("./a", "filer/readme.txt"), "./a/filer/readme.txt");
+    }
 }
@@ -0,0 +1,194 @@
+use anyhow::{anyhow, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use serde_json::Value;
+
+//Rules in the style of .gitignore at the root of a catalog, they belong to the
+//directory they are in and the file itself is never indexed, downloaded or copied
+pub const IGNORE_FILE_NAME: &str = ".filerignore";
+
+struct Rule {
+    glob: GlobMatcher,
+    //"!pattern" takes back what an earlier rule ignored
+    negate: bool,
+    //"pattern/" only matches directories
+    dir_only: bool,
+}
+
+impl Rule {
+    //None for blank lines and comments
+    fn parse(line: &str) -> Result<Option<Self>> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let (negate, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            //"\#" and "\!" start with a literal character
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        //a pattern with a slash is relative to the root, otherwise it matches at any depth
+        let pattern = if pattern.contains('/') {
+            pattern.trim_start_matches('/').to_string()
+        } else {
+            String::from("**/") + pattern
+        };
+        let glob = GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .case_insensitive(cfg!(windows))
+            .build()
+            .map_err(|e| anyhow!("invalid pattern {:?}: {}", line, e))?
+            .compile_matcher();
+        Ok(Some(Rule {
+            glob,
+            negate,
+            dir_only,
+        }))
+    }
+
+    fn is_match(&self, path: &str, is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && self.glob.is_match(path)
+    }
+}
+
+fn parse_rules<'a>(lines: impl Iterator<Item = &'a str>) -> Result<Vec<Rule>> {
+    Ok(lines
+        .map(Rule::parse)
+        .collect::<Result<Vec<Option<Rule>>>>()?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+//"include": ["*.exe", "bin/"], "exclude": ["*.log"]
+fn config_rules(config: &Value, key: &str) -> Result<Vec<Rule>> {
+    let patterns = match &config[key] {
+        Value::Null => return Ok(Vec::new()),
+        Value::Array(patterns) => patterns,
+        _ => return Err(anyhow!("{} must be a list of patterns", key)),
+    };
+    let patterns = patterns
+        .iter()
+        .map(|x| {
+            x.as_str()
+                .ok_or_else(|| anyhow!("invalid {} pattern {}", key, x))
+        })
+        .collect::<Result<Vec<&str>>>()?;
+    parse_rules(patterns.into_iter())
+}
+
+//Which files of a catalog are indexed, downloaded or copied, paths are relative with / separators.
+//A rule matching a directory applies to everything below it
+#[derive(Default)]
+pub struct FileFilter {
+    //every file when empty
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+    //the last matching line decides, like .gitignore
+    ignore: Vec<Rule>,
+}
+
+impl FileFilter {
+    pub fn new(config: &Value, ignore_file: &str) -> Result<Self> {
+        Ok(FileFilter {
+            include: config_rules(config, "include")?,
+            exclude: config_rules(config, "exclude")?,
+            ignore: parse_rules(ignore_file.lines())
+                .map_err(|e| anyhow!("{}: {}", IGNORE_FILE_NAME, e))?,
+        })
+    }
+
+    //The include/exclude patterns of config and the .filerignore in path, when there is one
+    pub async fn load(config: &Value, path: &str) -> Result<Self> {
+        let ignore_file = String::from(path) + "/" + IGNORE_FILE_NAME;
+        let ignore_file = tokio::fs::read_to_string(&ignore_file)
+            .await
+            .unwrap_or_default();
+        FileFilter::new(config, &ignore_file)
+    }
+
+    fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        self.exclude.iter().any(|x| x.is_match(path, is_dir))
+            || self
+                .ignore
+                .iter()
+                .rev()
+                .find(|x| x.is_match(path, is_dir))
+                .is_some_and(|x| !x.negate)
+    }
+
+    //Whether a walk descends into dir, a file below it may still be left out
+    pub fn is_dir_included(&self, dir: &str) -> bool {
+        !self.is_ignored(dir, true)
+    }
+
+    pub fn is_included(&self, file: &str) -> bool {
+        if file == IGNORE_FILE_NAME {
+            return false;
+        }
+        let dirs: Vec<&str> = file.match_indices('/').map(|(i, _)| &file[..i]).collect();
+        if dirs.iter().any(|x| self.is_ignored(x, true)) || self.is_ignored(file, false) {
+            return false;
+        }
+        self.include.is_empty()
+            || self.include.iter().any(|x| x.is_match(file, false))
+            || dirs
+                .iter()
+                .any(|dir| self.include.iter().any(|x| x.is_match(dir, true)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_ignore_file() {
+        let ignore_file = "# build output\n\
+                           *.log\n\
+                           !keep.log\n\
+                           target/\n\
+                           /docs/*.md\n\
+                           \\#notes\n\
+                           cache\n";
+        let filter = FileFilter::new(&Value::Null, ignore_file).unwrap();
+        assert!(filter.is_included("app.exe"));
+        assert!(!filter.is_included(IGNORE_FILE_NAME));
+        assert!(!filter.is_included("app.log"));
+        assert!(!filter.is_included("sub/app.log"));
+        assert!(filter.is_included("sub/keep.log"));
+        //only the directory named target, not a file
+        assert!(!filter.is_included("target/app.exe"));
+        assert!(!filter.is_included("sub/target/app.exe"));
+        assert!(filter.is_included("sub/target"));
+        assert!(!filter.is_dir_included("sub/target"));
+        //anchored to the root, * stays within one directory
+        assert!(!filter.is_included("docs/readme.md"));
+        assert!(filter.is_included("sub/docs/readme.md"));
+        assert!(filter.is_included("docs/api/readme.md"));
+        assert!(!filter.is_included("#notes"));
+        assert!(!filter.is_included("cache"));
+        assert!(!filter.is_included("cache/data.bin"));
+    }
+
+    #[test]
+    fn test_include_exclude() {
+        let config = json!({"include": ["*.exe", "bin/"], "exclude": ["bin/debug/", "old*"]});
+        let filter = FileFilter::new(&config, "").unwrap();
+        assert!(filter.is_included("filer.exe"));
+        assert!(filter.is_included("sub/app.exe"));
+        assert!(filter.is_included("bin/app.dll"));
+        assert!(!filter.is_included("app.dll"));
+        assert!(!filter.is_included("bin/debug/app.dll"));
+        assert!(!filter.is_included("old.exe"));
+        assert!(filter.is_dir_included("lib"));
+        assert!(FileFilter::default().is_included("any/file"));
+
+        assert!(FileFilter::new(&json!({"include": "*.exe"}), "").is_err());
+        assert!(FileFilter::new(&json!({"exclude": ["a[b"]}), "").is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use crate::context::AppContext;
+use crate::download;
+use crate::manifest;
+#[cfg(feature = "client-gui")]
+use crate::fileutil::refresh_dir_files_digest;
+use crate::json_helper::JsonHelper;
+#[cfg(feature = "client-gui")]
+use crate::{server, SERVER_HANDLE};
+use crate::{time_taken, MyResult};
+use byte_unit::Byte;
+use iced::{
+    button, text_input, scrollable, Button,
+    Column, Container, Element, Length, Row, Application,
+    Settings, Text, TextInput, Command, Clipboard, Scrollable,
+    Align
+};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use tokio::time::Instant;
+
+pub fn run() -> iced::Result {
+    Gui::run(Settings {
+        default_font: Some(include_bytes!(
+          "./hei.ttf"
+        // "./fangzheng.ttf"
+        // "./simhei.ttf"
+        )),
+        ..Settings::default()
+    })
+}
+
+#[derive(Default)]
+struct Gui {
+    input: text_input::State,
+    input_value: String,
+    get_file_list_button: button::State,
+    download_button: button::State,
+    message_tip: String,
+    file_list: Vec<FileInfo>,
+    remote_file_getted: bool,
+    is_downloading: bool,
+    path: PathBuf,
+    scrollable: scrollable::State,
+}
+
+struct FileInfo {
+    path: String,
+    size: String,
+    // scrollable: scrollable::State,
+}
+
+#[derive(Debug, Clone)]
+enum Message {
+    InputChanged(String),
+    GetFileListButtonPressed,
+    DownLoadButtonPressed,
+    ServerStarted(bool),
+    RemoteFileListGetted((bool, String)),
+    DownloadCompleted(bool)
+}
+
+
+impl Application for Gui {
+    type Message = Message;
+    type Executor = iced::executor::Default;
+    type Flags = ();
+
+    fn new(_flags: ()) -> (Self, Command<Message>) {
+        (
+            Gui::default(),
+            Command::none()
+        )
+    }
+
+    fn title(&self) -> String {
+        #[cfg(feature = "server-gui")]
+        return String::from("Server");
+
+        #[cfg(feature = "client-gui")]
+        return String::from("Client");
+    }
+
+    fn update(&mut self, message: Message, _:&mut Clipboard) -> Command<Message> {
+        match message {
+            Message::InputChanged(value) => self.input_value = value,
+            Message::ServerStarted(started) => {
+                println!("ServerStarted: {started}");
+
+                // 显示filelist.json或filelist.txt
+                if started {
+                    let path = fs::canonicalize(PathBuf::from(&self.path)).unwrap_or_else(|_| PathBuf::from(&self.path));
+                    println!("{path:?}");
+
+                    let bytes = fs::read(path.join(manifest::MANIFEST_NAME)).or_else(|_| fs::read(path.join(manifest::LEGACY_LIST_NAME)));
+                    match bytes.map_err(|e| e.to_string()).and_then(|bytes| manifest::Manifest::parse("", &bytes).map_err(|e| e.to_string())) {
+                        Err(e) => self.message_tip = format!("Failed to read local file list: {e}"),
+                        Ok(manifest) => {
+                            let mut totle = Vec::new();
+                            let mut total_size: u128 = 0;
+                            for file in &manifest.files {
+                                total_size += file.size as u128;
+                                let size = Byte::from_bytes(file.size as u128).get_appropriate_unit(false);
+                                totle.push(FileInfo{path: file.path.clone(), size: format!("{size}")});
+                            }
+                            self.file_list = totle;
+                            self.message_tip = format!("Total {} files with size {}.", manifest.files.len(), Byte::from_bytes(total_size).get_appropriate_unit(false));
+                        }
+                    }
+                } else {
+                    self.message_tip = "Failed to start service".to_owned();
+                }
+            },
+            Message::RemoteFileListGetted((geted, file_list)) => {
+                if !geted {
+                    println!("Failed to get remote filelist.txt");
+                    self.message_tip = format!("Failed to get remote filelist.txt");
+                    self.remote_file_getted = false;
+                } else {
+                    self.remote_file_getted = true;
+                    let remote_file_list = manifest::parse_file_list(&file_list).unwrap_or_default();
+                    let file_count = remote_file_list.len();
+
+                    let mut totle = Vec::new();
+
+                    let total_size = remote_file_list.iter().map(|x| x.1).sum::<u64>();
+    
+                    for file_info in remote_file_list {
+                        totle.push(FileInfo{path: file_info.2.to_owned(), size: format!("{}", Byte::from_bytes(file_info.1 as u128).get_appropriate_unit(false))});
+                    }
+
+                    self.file_list = totle;
+                    self.message_tip = format!("Total {} files with size {}.", file_count, Byte::from_bytes(total_size as u128).get_appropriate_unit(false));
+                }
+            },
+            Message::DownLoadButtonPressed => {
+                if !self.remote_file_getted {
+                    self.message_tip = "Please get file list first.".to_owned();
+                    return Command::none();
+                }
+
+                if self.is_downloading {
+                    return Command::none();
+                }
+
+                self.is_downloading = true;
+
+                return Command::perform(
+                    handle_start_download(),
+                    |completed| {
+                        println!("Download Completed");
+                        Message::DownloadCompleted(completed)
+                    }
+                )
+            }
+            Message::DownloadCompleted(completed) => {
+                if completed {
+                    self.message_tip = "Download completed".to_owned();
+                } else {
+                    self.message_tip = "Download error".to_owned();
+                }
+                self.is_downloading = false;
+            }
+            Message::GetFileListButtonPressed => {
+                println!("{}", self.input_value);
+
+                #[cfg(feature = "client-gui")]
+                {
+                    let path = "./demo_sent";
+                    // let path = if &self.input_value == "" { "./" } else { &self.input_value };
+
+                    match fs::metadata(path) {
+                        Ok(metadata) if metadata.is_dir() => {
+                            println!("is dir: {path}");
+                            self.message_tip = format!("Reading files in {path}......");
+                            self.path = PathBuf::from(path);
+                            return Command::perform(
+                                handle_start_server(path.to_owned()),
+                                |result| {
+                                    match result {
+                                        Ok(_) =>  Message::ServerStarted(true),
+                                        _ =>  Message::ServerStarted(false)
+                                    }
+                                },
+                            );
+                        },
+                        _ => {
+                            self.message_tip = format!("{path} is not a dirctory.");
+                            self.input_value = "".to_owned();
+                            return Command::none()
+                        }
+                    };
+                }
+
+                #[cfg(feature = "server-gui")]
+                {
+                    // println!("server-gui")
+                    self.message_tip = "Getting file list ...".to_owned();
+                    self.file_list.clear();
+
+
+                    let context = AppContext::new();
+                    let catalog = "tcsoftV6";
+
+                    return Command::perform(
+                        get_remote_file_list(context.config.clone(), catalog),
+                        |file_list| {
+                            match file_list {
+                                Ok(file_list) => Message::RemoteFileListGetted((true, file_list)),
+                                Err(_) => Message::RemoteFileListGetted((false, String::new()))
+                            }
+                        },
+                    );
+                }
+
+            }
+        }
+
+        Command::none()
+    }
+
+    fn view(&mut self) -> Element<Message> {
+ 
+        let text_input = TextInput::new(
+            &mut self.input,
+            "Type file directory, default: ./",
+            &self.input_value,
+            Message::InputChanged,
+        )
+        .padding(10)
+        .size(20);
+
+        #[cfg(feature = "client-gui")]
+        let button_text = "Get Local File List";
+
+        #[cfg(feature = "server-gui")]
+        let button_text = "Get Remote File List";
+
+        let button = Button::new(&mut self.get_file_list_button, Text::new(button_text))
+            .padding(10)
+            .on_press(Message::GetFileListButtonPressed);
+        
+                
+        let message_tip: Text = Text::new(&self.message_tip).into();
+
+
+        let mut file_list_scrollable = Scrollable::new(&mut self.scrollable)
+            .padding(10)
+            .spacing(10)
+            .scrollbar_margin(0)
+            .scrollbar_width(6)
+            .scroller_width(5)
+            .width(Length::Fill)
+            .height(Length::Fill);
+
+        for file_info in &self.file_list {
+            file_list_scrollable = file_list_scrollable.push(
+                Row::new()
+                    .push(
+                        Column::new()
+                            .push(
+                                Text::new(file_info.path.clone())
+                            )
+                            .width(Length::Fill)
+                            .align_items(Align::Start)
+                        )
+                    .push(
+                        Column::new()
+                        .push(
+                            Text::new(file_info.size.clone())
+                        )
+                        .width(Length::Shrink)
+                        .align_items(Align::End)
+                    )
+            )
+        };
+
+        // server端的下载按钮
+        #[cfg(feature = "server-gui")]
+        let footer = Column::with_children(vec![
+            Button::new(&mut self.download_button, Text::new(if self.is_downloading {"Downloading"} else {"Download"}))
+                .padding(10)
+                .on_press(Message::DownLoadButtonPressed)
+                .into(),
+        ])
+        .width(Length::Fill)
+        .align_items(Align::End);
+        // server端的进度条
+
+        let content = Column::new()
+            .spacing(20)
+            .padding(20)
+            // .max_width(600)
+            .width(Length::Fill)
+            // .align_items(Align::Center)
+            .push(
+                Row::new()
+                    .spacing(10)
+                    // .push(text_input)
+                    .push(button)
+                )
+            .push(message_tip)
+            .push(file_list_scrollable);
+        
+        #[cfg(feature = "server-gui")]
+        let content = content.push(footer);
+
+        Container::new(content)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            // .center_y()
+            .into()
+    }
+}
+
+async fn get_remote_file_list(config: Value, catalog: &str) -> MyResult<String> {
+    println!("Start downloading filelist.txt");
+    let client_config = &config["client"];
+    let catalog = client_config["catalog"].string(catalog);
+    let context = download::DownloadContext::new(client_config, &catalog).await?;
+    let public_key = client_config["public_key"].str("");
+    let remote_file_list = download::get_manifest(&context, public_key).await?.to_legacy();
+
+    println!("filelist.txt getted");
+    Ok(remote_file_list)
+}
+
+async fn handle_start_download() -> bool {
+    let context = AppContext::new();
+
+    let cpus = num_cpus::get() as u64;
+    let time_start = Instant::now();
+
+    let catalog = "tcsoftV6";
+    let res = download::download_files(
+        &context.config,
+        true,
+        cpus * 4,
+        catalog,
+        false,
+    )
+    .await;
+
+    match res {
+        Ok(_) => {
+            let pcpus = num_cpus::get_physical() as u64;
+            println!(
+                "Time taken: {}\nNumber of CPU cores: {}x{}",
+                time_taken(time_start),
+                pcpus,
+                cpus / pcpus
+            );
+            return true;
+        },
+        Err(e) => {
+            println!("Download Error: {e}");
+            return false;
+        }
+    }
+}
+
+#[cfg(feature = "client-gui")]
+async fn handle_start_server(path: String) -> MyResult<()> {
+    
+
+    println!("Recevied {path}");
+    
+    let context = AppContext::new();
+    let cpus = num_cpus::get() as u64;
+    // let time_start = Instant::now();
+
+    // let catalog = args.value_of("catalog").unwrap_or("tcsoftV6");
+    let catalog = "tcsoftV6";
+    let config = context.config[catalog].clone();
+    let part_size = config["part_size"].u64(102400u64);
+    let chunk_size = config["chunk_size"].u64(crate::chunking::DEFAULT_CHUNK_SIZE);
+    let max_tasks = config["max_tasks"].u64(cpus * 2);
+
+    let show_repeat = true;
+
+    let signing_key = config["signing_key"].str("");
+    let filter = crate::filter::FileFilter::load(&config, &path).await?;
+    let follow_links = config["follow_links"].bool(false);
+    refresh_dir_files_digest(catalog, &path, "filelist.txt", signing_key, part_size, chunk_size, max_tasks, show_repeat, false, &filter, follow_links)
+        .await?;
+
+    tokio::spawn(async {
+        println!("Shutdown start=========================================================================");
+        // clear all prev server
+        let mut handles = SERVER_HANDLE.lock().await;
+
+        loop {
+            if let Some(handle) = handles.pop() {
+                println!("Shutdown prev server ...");
+                handle.shutdown();
+            } else {
+                println!("Break Shutdown prev server loop");
+                break;
+            }
+        }
+
+        println!("Shutdown end ================================================================================")
+
+    });
+
+    // * Server Start ====================================
+
+    tokio::spawn(async move {
+        println!("Start server...");
+        server(&context).await;
+    });
+
+    // let _ = tokio::join!(server_task);
+
+    println!("Server started!");
+
+    // * Server End ====================================
+    Ok(())
+}
+
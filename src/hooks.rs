@@ -0,0 +1,230 @@
+use crate::json_helper::JsonHelper;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::process::Command;
+
+//Above this the lists are only passed as files, environments are small on Windows
+const LIST_ENV_LIMIT: usize = 16 * 1024;
+
+//Commands of a catalog run through the shell around a download that changes something,
+//"pre_download": "systemctl stop demo", "post_download": "systemctl start demo", "hook_timeout": 300
+//Once pre_download ran, post_download runs even when the sync fails, with FILER_STATUS=failed
+pub struct Hooks {
+    pre_download: Option<String>,
+    post_download: Option<String>,
+    timeout: Duration,
+}
+
+//What the run changes, handed to the hooks as environment variables
+pub struct HookEnv<'a> {
+    pub catalog: &'a str,
+    pub path: &'a str,
+    pub changed: Vec<&'a str>,
+    pub removed: Vec<&'a str>,
+}
+
+impl Hooks {
+    pub fn from_config(catalog_config: &Value) -> Self {
+        let command = |key: &str| {
+            Some(catalog_config[key].str("").trim().to_string()).filter(|x| !x.is_empty())
+        };
+        Hooks {
+            pre_download: command("pre_download"),
+            post_download: command("post_download"),
+            timeout: Duration::from_secs(catalog_config["hook_timeout"].u64(300)),
+        }
+    }
+
+    pub async fn pre_download(&self, env: &HookEnv<'_>) -> Result<()> {
+        match &self.pre_download {
+            Some(command) => self.run("pre_download", command, env, None).await,
+            None => Ok(()),
+        }
+    }
+
+    //Also run after a failed sync, so what pre_download stopped is started again
+    pub async fn post_download(&self, env: &HookEnv<'_>, succeeded: bool) -> Result<()> {
+        let status = if succeeded { "ok" } else { "failed" };
+        match &self.post_download {
+            Some(command) => self.run("post_download", command, env, Some(status)).await,
+            None => Ok(()),
+        }
+    }
+
+    //An error stops the sync, a hook which does not finish in time is killed
+    async fn run(
+        &self,
+        hook: &str,
+        command: &str,
+        env: &HookEnv<'_>,
+        status: Option<&str>,
+    ) -> Result<()> {
+        println!("Run {} hook: {}", hook, command);
+        let changed = env.changed.join("\n");
+        let removed = env.removed.join("\n");
+        let changed_list = ListFile::create(hook, "changed", &changed)?;
+        let removed_list = ListFile::create(hook, "removed", &removed)?;
+        let (changed, removed) = if changed.len() + removed.len() > LIST_ENV_LIMIT {
+            (String::new(), String::new())
+        } else {
+            (changed, removed)
+        };
+
+        let mut shell = if cfg!(windows) {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        let status = shell
+            .arg(command)
+            .env("FILER_HOOK", hook)
+            .env("FILER_CATALOG", env.catalog)
+            .env("FILER_PATH", env.path)
+            .env("FILER_CHANGED_COUNT", env.changed.len().to_string())
+            .env("FILER_REMOVED_COUNT", env.removed.len().to_string())
+            .env("FILER_CHANGED_FILES", changed)
+            .env("FILER_REMOVED_FILES", removed)
+            .env("FILER_CHANGED_LIST", &changed_list.0)
+            .env("FILER_REMOVED_LIST", &removed_list.0)
+            .envs(status.map(|x| ("FILER_STATUS", x)))
+            .kill_on_drop(true)
+            .status();
+        let status = tokio::time::timeout(self.timeout, status).await;
+        drop((changed_list, removed_list));
+        let status = status.map_err(|_| {
+            anyhow!(
+                "{} hook did not finish in {}s",
+                hook,
+                self.timeout.as_secs()
+            )
+        })??;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!("{} hook failed with {}", hook, status))
+        }
+    }
+}
+
+//A list handed to a hook, under a random name nobody can create in advance,
+//removed again once the hook is done
+struct ListFile(std::path::PathBuf);
+
+impl ListFile {
+    fn create(hook: &str, name: &str, content: &str) -> Result<Self> {
+        use std::collections::hash_map::RandomState;
+        use std::hash::{BuildHasher, Hasher};
+        use std::io::Write;
+        let mut tries = 0;
+        loop {
+            let random = RandomState::new().build_hasher().finish();
+            let file_name = std::env::temp_dir().join(format!(
+                "filer-{}-{}-{}-{:016x}.txt",
+                std::process::id(),
+                hook,
+                name,
+                random
+            ));
+            //create_new never follows a link put in place of the file
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&file_name) {
+                Ok(mut file) => {
+                    let list = ListFile(file_name);
+                    file.write_all(content.as_bytes())?;
+                    return Ok(list);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && tries < 8 => {
+                    tries += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for ListFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileutil::TempPath;
+    use serde_json::json;
+
+    #[test]
+    fn test_hooks_from_config() {
+        let hooks = Hooks::from_config(&json!({"pre_download": " ", "post_download": "true"}));
+        assert_eq!(hooks.pre_download, None);
+        assert_eq!(hooks.post_download.as_deref(), Some("true"));
+        assert_eq!(hooks.timeout, Duration::from_secs(300));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_hooks() {
+        let output_file = TempPath::new("hook.txt");
+        let output = output_file.str();
+        let env = HookEnv {
+            catalog: "demo",
+            path: "./demo_recv",
+            changed: vec!["a.exe", "sub/b.dll"],
+            removed: vec!["old.txt"],
+        };
+        let hooks = Hooks {
+            pre_download: Some(format!(
+                "echo \"$FILER_HOOK $FILER_CATALOG $FILER_CHANGED_COUNT $FILER_REMOVED_FILES\" > {} && cat \"$FILER_CHANGED_LIST\" >> {}",
+                output, output
+            )),
+            post_download: Some(format!("printf %s \"$FILER_STATUS\" > {} && exit 2", output)),
+            timeout: Duration::from_secs(5),
+        };
+        hooks.pre_download(&env).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "pre_download demo 2 old.txt\na.exe\nsub/b.dll"
+        );
+        assert!(hooks.post_download(&env, false).await.is_err());
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "failed");
+
+        let slow = Hooks {
+            pre_download: Some("sleep 5".to_string()),
+            post_download: None,
+            timeout: Duration::from_millis(200),
+        };
+        assert!(slow.pre_download(&env).await.is_err());
+        assert!(slow.post_download(&env, true).await.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hook_lists_removed() {
+        let output_file = TempPath::new("hook-lists.txt");
+        let output = output_file.str();
+        let env = HookEnv {
+            catalog: "demo",
+            path: "./demo_recv",
+            changed: vec!["a.exe"],
+            removed: vec![],
+        };
+        let hooks = Hooks {
+            pre_download: Some(format!("printf %s \"$FILER_CHANGED_LIST\" > {}", output)),
+            post_download: None,
+            timeout: Duration::from_secs(5),
+        };
+        hooks.pre_download(&env).await.unwrap();
+        let list = std::fs::read_to_string(output).unwrap();
+        assert!(list.starts_with(std::env::temp_dir().to_str().unwrap()));
+        assert!(std::fs::metadata(&list).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::fileutil::write_file_atomic;
+use crate::manifest::ManifestChunk;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+//Sidecar of the file list, <list_name>.filer-cache, never listed itself
+pub const CACHE_SUFFIX: &str = ".filer-cache";
+const CACHE_VERSION: u64 = 1;
+//Files modified this close to the index run may change again within the same mtime tick
+const RACY_SECS: u64 = 2;
+
+//What identifies an unchanged file without reading it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+    //0 where the platform has no stable inode
+    pub inode: u64,
+    //permission bits for the manifest, a chmod alone does not need hashing again
+    #[serde(default)]
+    pub mode: u32,
+    //owner for the manifest, like mode
+    #[serde(default)]
+    pub uid: u32,
+    #[serde(default)]
+    pub gid: u32,
+}
+
+impl FileStamp {
+    pub fn new(meta: &Metadata) -> Self {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(meta);
+        #[cfg(not(unix))]
+        let inode = 0;
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o7777;
+        #[cfg(not(unix))]
+        let mode = 0;
+        #[cfg(unix)]
+        let (uid, gid) = (
+            std::os::unix::fs::MetadataExt::uid(meta),
+            std::os::unix::fs::MetadataExt::gid(meta),
+        );
+        #[cfg(not(unix))]
+        let (uid, gid) = (0, 0);
+        FileStamp {
+            size: meta.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            inode,
+            mode,
+            uid,
+            gid,
+        }
+    }
+
+    fn unchanged(&self, other: &FileStamp) -> bool {
+        self.size == other.size
+            && self.mtime_secs == other.mtime_secs
+            && self.mtime_nanos == other.mtime_nanos
+            && self.inode == other.inode
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct CacheEntry {
+    #[serde(flatten)]
+    stamp: FileStamp,
+    digest: String,
+    #[serde(default)]
+    chunks: Vec<ManifestChunk>,
+}
+
+//(file name relative to the catalog path) -> stamp and digest of the last index run
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct IndexCache {
+    version: u64,
+    //chunks were cut with this average size, another size means chunking again
+    #[serde(default)]
+    chunk_size: u64,
+    files: HashMap<String, CacheEntry>,
+    //entries used or added by the current run, the only ones saved
+    #[serde(skip)]
+    seen: HashMap<String, CacheEntry>,
+    #[serde(skip)]
+    started_secs: u64,
+}
+
+impl IndexCache {
+    //An unreadable or outdated cache is the same as no cache, every file is hashed again
+    pub async fn load(cache_file_name: &str, chunk_size: u64) -> Self {
+        let files = match fs::read(cache_file_name).await {
+            Ok(bytes) => serde_json::from_slice::<IndexCache>(&bytes)
+                .ok()
+                .filter(|x| x.version == CACHE_VERSION && x.chunk_size == chunk_size)
+                .map(|x| x.files)
+                .unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+        IndexCache {
+            files,
+            ..IndexCache::empty(chunk_size)
+        }
+    }
+
+    pub fn empty(chunk_size: u64) -> Self {
+        IndexCache {
+            version: CACHE_VERSION,
+            chunk_size,
+            started_secs: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or(0),
+            ..Default::default()
+        }
+    }
+
+    //digest and chunks of file_name from the last run when its stamp is unchanged
+    pub fn lookup(
+        &mut self,
+        file_name: &str,
+        stamp: &FileStamp,
+    ) -> Option<(String, Vec<ManifestChunk>)> {
+        let entry = self.files.get(file_name).filter(|x| x.stamp.unchanged(stamp))?.clone();
+        let result = (entry.digest.clone(), entry.chunks.clone());
+        self.seen.insert(file_name.to_string(), entry);
+        Some(result)
+    }
+
+    //stamp must be taken before hashing, so a write during hashing invalidates the entry
+    pub fn insert(
+        &mut self,
+        file_name: &str,
+        stamp: FileStamp,
+        digest: &str,
+        chunks: &[ManifestChunk],
+    ) {
+        if stamp.mtime_secs + RACY_SECS > self.started_secs {
+            return;
+        }
+        let digest = digest.to_string();
+        let chunks = chunks.to_vec();
+        self.seen.insert(file_name.to_string(), CacheEntry { stamp, digest, chunks });
+    }
+
+    //Keep only the files of this run, deleted files drop out of the cache
+    pub async fn save(mut self, cache_file_name: &str) -> anyhow::Result<()> {
+        self.files = std::mem::take(&mut self.seen);
+        write_file_atomic(cache_file_name, &serde_json::to_vec(&self)?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileutil::TempPath;
+
+    #[tokio::test]
+    async fn test_index_cache() {
+        let cache_file = TempPath::new(&(String::from("list") + CACHE_SUFFIX));
+        let cache_file_name = cache_file.str();
+        let stamp = FileStamp {
+            size: 10,
+            mtime_secs: 1000,
+            mtime_nanos: 5,
+            inode: 42,
+            mode: 0o644,
+            uid: 1000,
+            gid: 1000,
+        };
+        let chunks = vec![ManifestChunk {
+            size: 10,
+            digest: "c1".to_string(),
+        }];
+        let hit = |digest: &str| Some((digest.to_string(), Vec::new()));
+        let mut cache = IndexCache::empty(0);
+        cache.insert("a.txt", stamp, "d1", &[]);
+        cache.insert("gone.txt", stamp, "d2", &chunks);
+        //modified just now, may still change within the same mtime tick
+        let racy = FileStamp {
+            mtime_secs: cache.started_secs,
+            ..stamp
+        };
+        cache.insert("racy.txt", racy, "d3", &[]);
+        cache.save(cache_file_name).await.unwrap();
+
+        let mut cache = IndexCache::load(cache_file_name, 0).await;
+        assert_eq!(cache.lookup("a.txt", &stamp), hit("d1"));
+        assert_eq!(cache.lookup("racy.txt", &racy), None);
+        let chmod = FileStamp { mode: 0o755, ..stamp };
+        assert_eq!(cache.lookup("a.txt", &chmod), hit("d1"));
+        for changed in [
+            FileStamp { size: 11, ..stamp },
+            FileStamp { mtime_nanos: 6, ..stamp },
+            FileStamp { inode: 43, ..stamp },
+        ] {
+            assert_eq!(cache.lookup("gone.txt", &changed), None);
+        }
+        cache.save(cache_file_name).await.unwrap();
+
+        //only the files seen by the last run are kept
+        let mut cache = IndexCache::load(cache_file_name, 0).await;
+        assert_eq!(cache.lookup("a.txt", &stamp), hit("d1"));
+        assert_eq!(cache.lookup("gone.txt", &stamp), None);
+        cache.insert("chunked.bin", stamp, "d4", &chunks);
+        cache.save(cache_file_name).await.unwrap();
+        let mut cache = IndexCache::load(cache_file_name, 0).await;
+        assert_eq!(cache.lookup("chunked.bin", &stamp), Some(("d4".to_string(), chunks)));
+
+        //chunks cut with another size are useless
+        let mut cache = IndexCache::load(cache_file_name, 4096).await;
+        assert_eq!(cache.lookup("a.txt", &stamp), None);
+
+        fs::write(cache_file_name, "{torn").await.unwrap();
+        let mut cache = IndexCache::load(cache_file_name, 0).await;
+        assert_eq!(cache.lookup("a.txt", &stamp), None);
+    }
+}
@@ -1,3 +1,4 @@
+#![allow(dead_code)]
 use serde_json::Value;
 pub trait JsonHelper {
     //fn str(&self, default: &str) -> &str;
@@ -0,0 +1,607 @@
+use crate::fileutil::is_link_inside;
+#[cfg(any(feature = "digest", feature = "download"))]
+use crate::fileutil::EOL;
+use anyhow::{anyhow, Result};
+use byte_unit::Byte;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+//Versioned manifest written by index next to the legacy list, fetched first by clients
+pub const MANIFEST_NAME: &str = "filelist.json";
+//digest,size,path lines joined by EOL, still written for older clients
+#[cfg(feature = "download")]
+pub const LEGACY_LIST_NAME: &str = "filelist.txt";
+pub const MANIFEST_FORMAT: &str = "filer-manifest";
+//Readers refuse newer versions, fields added within a version must be optional
+pub const MANIFEST_VERSION: u64 = 1;
+pub const HASH_ALGORITHM: &str = "blake3";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestHeader {
+    pub format: String,
+    pub version: u64,
+    pub catalog: String,
+    //unix seconds, 0 when converted from a legacy list
+    pub generated_at: u64,
+    pub hash_algorithm: String,
+    pub tool_version: String,
+    //average FastCDC chunk size used for "chunks", 0 when no file is chunked
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub chunk_size: u64,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+//One content-defined chunk, chunks follow each other from offset 0
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestChunk {
+    pub size: u64,
+    pub digest: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    //relative to the catalog path, '/' separated
+    pub path: String,
+    pub size: u64,
+    pub digest: String,
+    //unix seconds, unknown in legacy lists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    //permission bits, unix only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    //owner ids, unix only, restored only when the client asks for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    //a symlink with this target instead of a file, size is 0 and digest is the hash of the target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    //only large files are chunked, so a changed file can be patched from its old copy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<ManifestChunk>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub header: ManifestHeader,
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new(catalog: &str, files: Vec<ManifestEntry>) -> Self {
+        let generated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        Manifest {
+            header: ManifestHeader {
+                format: MANIFEST_FORMAT.to_string(),
+                version: MANIFEST_VERSION,
+                catalog: catalog.to_string(),
+                generated_at,
+                hash_algorithm: HASH_ALGORITHM.to_string(),
+                tool_version: env!("CARGO_PKG_VERSION").to_string(),
+                chunk_size: 0,
+            },
+            files,
+        }
+    }
+
+    //Either a JSON manifest or a legacy digest,size,path list, told apart by the first byte
+    pub fn parse(catalog: &str, bytes: &[u8]) -> Result<Self, ManifestError> {
+        let text = std::str::from_utf8(bytes).map_err(ManifestError::Utf8)?;
+        if !text.trim_start().starts_with('{') {
+            return Manifest::from_legacy(catalog, text);
+        }
+        let manifest: Manifest = serde_json::from_str(text).map_err(ManifestError::Json)?;
+        let header = &manifest.header;
+        if header.format != MANIFEST_FORMAT {
+            return Err(ManifestError::Format(header.format.clone()));
+        }
+        if header.version > MANIFEST_VERSION {
+            return Err(ManifestError::Version(header.version));
+        }
+        if header.hash_algorithm != HASH_ALGORITHM {
+            return Err(ManifestError::HashAlgorithm(header.hash_algorithm.clone()));
+        }
+        let mut paths = HashSet::new();
+        let links: HashSet<&str> = manifest.links().into_iter().map(|x| x.0).collect();
+        for (index, entry) in manifest.files.iter().enumerate() {
+            let error = |kind| ManifestError::Entry { index, kind };
+            check_digest(&entry.digest).map_err(error)?;
+            check_path(&entry.path).map_err(error)?;
+            check_chunks(entry, header.chunk_size).map_err(error)?;
+            check_link(entry, &links).map_err(error)?;
+            if !paths.insert(entry.path.as_str()) {
+                return Err(error(EntryError::DuplicatePath(entry.path.clone())));
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn from_legacy(catalog: &str, text: &str) -> Result<Self, ManifestError> {
+        let files = parse_file_list(text)?
+            .into_iter()
+            .map(|(digest, size, path)| ManifestEntry {
+                path: path.to_string(),
+                size,
+                digest: digest.to_string(),
+                mtime: None,
+                mode: None,
+                uid: None,
+                gid: None,
+                link: None,
+                chunks: Vec::new(),
+            })
+            .collect();
+        let mut manifest = Manifest::new(catalog, files);
+        manifest.header.generated_at = 0;
+        manifest.header.tool_version = String::new();
+        Ok(manifest)
+    }
+
+    #[cfg(any(feature = "digest", feature = "download"))]
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+
+    //older clients would download a link as a file, it is left out
+    #[cfg(any(feature = "digest", feature = "download"))]
+    pub fn to_legacy(&self) -> String {
+        self.files
+            .iter()
+            .filter(|x| x.link.is_none())
+            .map(|x| format!("{},{},{}", x.digest, x.size, x.path))
+            .collect::<Vec<String>>()
+            .join(EOL)
+    }
+
+    //[(digest,size,path)...] of the files as the sync code compares them, without links
+    #[cfg(feature = "download")]
+    pub fn file_list(&self) -> Vec<(&str, u64, &str)> {
+        self.files
+            .iter()
+            .filter(|x| x.link.is_none())
+            .map(|x| (x.digest.as_str(), x.size, x.path.as_str()))
+            .collect()
+    }
+
+    //Like file_list with the links, for finding what is gone from the server
+    #[cfg(feature = "download")]
+    pub fn entry_list(&self) -> Vec<(&str, u64, &str)> {
+        self.files
+            .iter()
+            .map(|x| (x.digest.as_str(), x.size, x.path.as_str()))
+            .collect()
+    }
+
+    //[(path,target)...]
+    pub fn links(&self) -> Vec<(&str, &str)> {
+        self.files
+            .iter()
+            .filter_map(|x| x.link.as_deref().map(|link| (x.path.as_str(), link)))
+            .collect()
+    }
+
+    //{path:(digest,size,mtime)} of files and links
+    #[cfg(feature = "server")]
+    pub fn digests(&self) -> HashMap<String, (String, u64, Option<u64>)> {
+        self.files
+            .iter()
+            .map(|x| (x.path.clone(), (x.digest.clone(), x.size, x.mtime)))
+            .collect()
+    }
+}
+
+//The manifest of a catalog folder, preferring filelist.json over the legacy list
+#[cfg(any(feature = "download", feature = "server"))]
+pub async fn load_manifest(path: &str, catalog: &str, list_name: &str) -> Result<Manifest> {
+    let bytes = match fs::read(String::from(path) + "/" + MANIFEST_NAME).await {
+        Ok(bytes) => bytes,
+        Err(_) => fs::read(String::from(path) + "/" + list_name).await?,
+    };
+    Ok(Manifest::parse(catalog, &bytes)?)
+}
+
+//filer verify-manifest: parse a manifest or legacy list and report the first problem
+pub async fn verify_manifest(file_name: &str) -> Result<()> {
+    let bytes = fs::read(file_name).await?;
+    let manifest =
+        Manifest::parse("", &bytes).map_err(|e| anyhow!("{} is invalid: {}", file_name, e))?;
+    let header = &manifest.header;
+    let total_size = manifest.files.iter().map(|x| x.size).sum::<u64>();
+    if !bytes.trim_ascii_start().starts_with(b"{") {
+        println!(
+            "{} is a valid legacy list, {} files with size {}",
+            file_name,
+            manifest.files.len(),
+            Byte::from_bytes(total_size as u128).get_appropriate_unit(false)
+        );
+        return Ok(());
+    }
+    println!(
+        "{} is valid: {} version {}, catalog {}, tool version {}, {} files with size {}",
+        file_name,
+        header.format,
+        header.version,
+        header.catalog,
+        header.tool_version,
+        manifest.files.len(),
+        Byte::from_bytes(total_size as u128).get_appropriate_unit(false)
+    );
+    Ok(())
+}
+
+//Why a manifest was refused, line is 1-based, index is the position in "files"
+#[derive(Debug)]
+pub enum ManifestError {
+    Utf8(std::str::Utf8Error),
+    Json(serde_json::Error),
+    Format(String),
+    Version(u64),
+    HashAlgorithm(String),
+    Line { line: usize, kind: EntryError },
+    Entry { index: usize, kind: EntryError },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EntryError {
+    MissingField(&'static str),
+    InvalidSize(String),
+    InvalidDigest(String),
+    UnsafePath(String),
+    DuplicatePath(String),
+    InvalidChunks(String),
+    InvalidLink(String),
+}
+
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryError::MissingField(field) => write!(f, "missing field {}", field),
+            EntryError::InvalidSize(size) => write!(f, "invalid size {:?}", size),
+            EntryError::InvalidDigest(digest) => {
+                write!(f, "invalid {} digest {:?}", HASH_ALGORITHM, digest)
+            }
+            EntryError::UnsafePath(path) => {
+                write!(f, "path {:?} is not relative to the catalog", path)
+            }
+            EntryError::DuplicatePath(path) => write!(f, "path {:?} is listed twice", path),
+            EntryError::InvalidChunks(reason) => write!(f, "invalid chunks: {}", reason),
+            EntryError::InvalidLink(reason) => write!(f, "invalid link: {}", reason),
+        }
+    }
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Utf8(e) => write!(f, "manifest is not utf-8: {}", e),
+            ManifestError::Json(e) => write!(f, "invalid manifest json: {}", e),
+            ManifestError::Format(format) => write!(f, "not a filer manifest: format {:?}", format),
+            ManifestError::Version(version) => write!(
+                f,
+                "manifest version {} is newer than supported version {}, update filer",
+                version, MANIFEST_VERSION
+            ),
+            ManifestError::HashAlgorithm(name) => {
+                write!(f, "unsupported hash algorithm {:?}", name)
+            }
+            ManifestError::Line { line, kind } => write!(f, "line {}: {}", line, kind),
+            ManifestError::Entry { index, kind } => write!(f, "files[{}]: {}", index, kind),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+fn check_digest(digest: &str) -> Result<(), EntryError> {
+    if digest.len() == 64 && digest.bytes().all(|x| x.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        Err(EntryError::InvalidDigest(digest.to_string()))
+    }
+}
+
+//Chunks must cover the file exactly, a gap would be patched with the wrong bytes
+fn check_chunks(entry: &ManifestEntry, chunk_size: u64) -> Result<(), EntryError> {
+    if entry.chunks.is_empty() {
+        return Ok(());
+    }
+    if chunk_size == 0 {
+        return Err(EntryError::InvalidChunks("header has no chunk_size".to_string()));
+    }
+    for chunk in &entry.chunks {
+        check_digest(&chunk.digest)?;
+        if chunk.size == 0 {
+            return Err(EntryError::InvalidChunks("empty chunk".to_string()));
+        }
+    }
+    let total = entry.chunks.iter().map(|x| x.size).sum::<u64>();
+    if total != entry.size {
+        return Err(EntryError::InvalidChunks(format!(
+            "chunk sizes add up to {}, file size is {}",
+            total, entry.size
+        )));
+    }
+    Ok(())
+}
+
+//Nothing may be written through a link, a file below one could end up anywhere
+fn check_link(entry: &ManifestEntry, links: &HashSet<&str>) -> Result<(), EntryError> {
+    if let Some(link) = &entry.link {
+        if link.is_empty() || entry.size != 0 || !entry.chunks.is_empty() {
+            return Err(EntryError::InvalidLink(format!(
+                "{:?} must have a target, no size and no chunks",
+                entry.path
+            )));
+        }
+        if !is_link_inside(&entry.path, link) {
+            return Err(EntryError::InvalidLink(format!(
+                "{:?} -> {:?} leads outside of the catalog",
+                entry.path, link
+            )));
+        }
+    }
+    let mut folder = Path::new(&entry.path).parent();
+    while let Some(parent) = folder {
+        if links.contains(parent.to_str().unwrap_or("")) {
+            return Err(EntryError::InvalidLink(format!(
+                "{:?} is below the link {:?}",
+                entry.path,
+                parent.display()
+            )));
+        }
+        folder = parent.parent();
+    }
+    Ok(())
+}
+
+//Entries are joined onto the client path, so only plain relative names are accepted
+fn check_path(path: &str) -> Result<(), EntryError> {
+    let is_relative = !path.is_empty()
+        && !path.starts_with('/')
+        && !path.contains('\\')
+        && Path::new(path)
+            .components()
+            .all(|x| matches!(x, Component::Normal(_) | Component::CurDir));
+    if is_relative {
+        Ok(())
+    } else {
+        Err(EntryError::UnsafePath(path.to_string()))
+    }
+}
+
+//One legacy digest,size,path line, the path may contain commas
+pub fn parse_line(line: &str) -> Result<(&str, u64, &str), EntryError> {
+    let mut parts = line.splitn(3, ',');
+    let digest = parts
+        .next()
+        .filter(|x| !x.is_empty())
+        .ok_or(EntryError::MissingField("digest"))?;
+    let size = parts.next().ok_or(EntryError::MissingField("size"))?;
+    let path = parts.next().ok_or(EntryError::MissingField("path"))?;
+    let size = size
+        .parse::<u64>()
+        .map_err(|_| EntryError::InvalidSize(size.to_string()))?;
+    check_digest(digest)?;
+    check_path(path)?;
+    Ok((digest, size, path))
+}
+
+//Legacy digest,size,path lines, return [(digest,size,path)...]
+pub fn parse_file_list(str: &str) -> Result<Vec<(&str, u64, &str)>, ManifestError> {
+    let mut paths = HashSet::new();
+    let mut file_list = Vec::new();
+    for (i, line) in str.split('\n').enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let error = |kind| ManifestError::Line { line: i + 1, kind };
+        let entry = parse_line(line).map_err(error)?;
+        if !paths.insert(entry.2) {
+            return Err(error(EntryError::DuplicatePath(entry.2.to_string())));
+        }
+        file_list.push(entry);
+    }
+    Ok(file_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const D1: &str = "0b8b60248fad7ac6dfac221b7e01a8b91c772421a15b387dd1fb2d6a94aee438";
+    const D2: &str = "ebbab94d14ee3ffe9e1f8e05634662908082428e26fe1e9b1feaeab6c79892e1";
+
+    #[cfg(all(feature = "download", feature = "server"))]
+    #[test]
+    fn test_manifest_parse() {
+        let legacy = format!("{},3,sub/a.txt\r\n{},10,b,c.bin", D1, D2);
+        let manifest = Manifest::parse("demo", legacy.as_bytes()).unwrap();
+        assert_eq!(manifest.header.version, MANIFEST_VERSION);
+        assert_eq!(
+            manifest.file_list(),
+            vec![(D1, 3, "sub/a.txt"), (D2, 10, "b,c.bin")]
+        );
+        assert_eq!(manifest.to_legacy(), legacy);
+
+        let mut manifest = Manifest::new("demo", manifest.files);
+        manifest.files[0].mtime = Some(1000);
+        manifest.files[0].mode = Some(0o644);
+        let json = manifest.to_json().unwrap();
+        let parsed = Manifest::parse("other", &json).unwrap();
+        assert_eq!(parsed, manifest);
+        assert_eq!(parsed.header.catalog, "demo");
+        assert_eq!(parsed.files[0].mode, Some(0o644));
+        assert_eq!(parsed.digests()["sub/a.txt"].0, parsed.files[0].digest);
+
+        //unknown fields are ignored, newer versions are refused
+        let mut value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        value["files"][0]["future_field"] = serde_json::json!(1);
+        assert!(Manifest::parse("demo", value.to_string().as_bytes()).is_ok());
+        let refused = |value: &serde_json::Value| {
+            Manifest::parse("demo", value.to_string().as_bytes()).unwrap_err()
+        };
+        value["header"]["version"] = serde_json::json!(MANIFEST_VERSION + 1);
+        assert!(matches!(refused(&value), ManifestError::Version(_)));
+        value["header"]["version"] = serde_json::json!(MANIFEST_VERSION);
+        value["header"]["format"] = serde_json::json!("other");
+        assert!(matches!(refused(&value), ManifestError::Format(_)));
+        value["header"]["format"] = serde_json::json!(MANIFEST_FORMAT);
+        value["files"][1]["path"] = serde_json::json!("../escape.exe");
+        assert!(matches!(
+            refused(&value),
+            ManifestError::Entry {
+                index: 1,
+                kind: EntryError::UnsafePath(_)
+            }
+        ));
+        value["files"][1]["path"] = serde_json::json!("b,c.bin");
+
+        //chunks must add up to the file size under a header chunk_size
+        value["files"][1]["chunks"] = serde_json::json!([
+            {"size": 4, "digest": D1},
+            {"size": 6, "digest": D2}
+        ]);
+        assert!(matches!(
+            refused(&value),
+            ManifestError::Entry {
+                index: 1,
+                kind: EntryError::InvalidChunks(_)
+            }
+        ));
+        value["header"]["chunk_size"] = serde_json::json!(4);
+        let parsed = Manifest::parse("demo", value.to_string().as_bytes()).unwrap();
+        assert_eq!(parsed.files[1].chunks.len(), 2);
+        value["files"][1]["chunks"][1]["size"] = serde_json::json!(5);
+        assert!(matches!(
+            refused(&value),
+            ManifestError::Entry {
+                index: 1,
+                kind: EntryError::InvalidChunks(_)
+            }
+        ));
+    }
+
+    #[cfg(feature = "download")]
+    #[test]
+    fn test_manifest_links() {
+        let entry = |path: &str, link: Option<&str>| ManifestEntry {
+            path: path.to_string(),
+            size: if link.is_some() { 0 } else { 3 },
+            digest: D1.to_string(),
+            mtime: None,
+            mode: None,
+            uid: None,
+            gid: None,
+            link: link.map(String::from),
+            chunks: Vec::new(),
+        };
+        let manifest = Manifest::new(
+            "demo",
+            vec![entry("bin/app", None), entry("current", Some("bin"))],
+        );
+        let parsed = Manifest::parse("demo", &manifest.to_json().unwrap()).unwrap();
+        assert_eq!(parsed.links(), vec![("current", "bin")]);
+        //links are neither synced as files nor listed for older clients
+        assert_eq!(parsed.file_list(), vec![(D1, 3, "bin/app")]);
+        assert_eq!(parsed.entry_list().len(), 2);
+        assert_eq!(parsed.to_legacy(), format!("{},3,bin/app", D1));
+
+        //nothing may be written through a link
+        let below = Manifest::new(
+            "demo",
+            vec![entry("current", Some("bin")), entry("current/passwd", None)],
+        );
+        assert!(matches!(
+            Manifest::parse("demo", &below.to_json().unwrap()).unwrap_err(),
+            ManifestError::Entry {
+                index: 1,
+                kind: EntryError::InvalidLink(_)
+            }
+        ));
+        //nor may a link point outside of the catalog
+        for target in ["/etc", "../..", "../bin/../../etc", "C:/Windows", "\\\\server\\share"] {
+            let outside = Manifest::new("demo", vec![entry("sub/current", Some(target))]);
+            assert!(Manifest::parse("demo", &outside.to_json().unwrap()).is_err());
+        }
+        let inside = Manifest::new("demo", vec![entry("sub/current", Some("../bin"))]);
+        assert!(Manifest::parse("demo", &inside.to_json().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_parse_file_list_errors() {
+        let line_error = |text: String| match parse_file_list(&text) {
+            Err(ManifestError::Line { line, kind }) => (line, kind),
+            other => panic!("expected a line error, got {:?}", other),
+        };
+        let good = format!("{},3,a.txt", D1);
+        assert_eq!(
+            line_error(format!("{}\r\n{},3", good, D2)),
+            (2, EntryError::MissingField("path"))
+        );
+        assert_eq!(
+            line_error(format!("{}\r\n\r\n{}", good, D2)),
+            (3, EntryError::MissingField("size"))
+        );
+        assert_eq!(
+            line_error(format!("{},x3,b.txt", D2)),
+            (1, EntryError::InvalidSize("x3".to_string()))
+        );
+        assert_eq!(
+            line_error("abc,3,b.txt".to_string()),
+            (1, EntryError::InvalidDigest("abc".to_string()))
+        );
+        assert_eq!(
+            line_error(format!("{},3,/etc/passwd", D2)),
+            (1, EntryError::UnsafePath("/etc/passwd".to_string()))
+        );
+        assert_eq!(
+            line_error(format!("{}\n{},4,a.txt", good, D2)),
+            (2, EntryError::DuplicatePath("a.txt".to_string()))
+        );
+        //a truncated file is reported, not a panic
+        let truncated = &good[..10];
+        assert_eq!(
+            line_error(truncated.to_string()),
+            (1, EntryError::MissingField("size"))
+        );
+        assert_eq!(parse_file_list("").unwrap(), vec![]);
+    }
+
+    #[cfg(any(feature = "digest", feature = "download"))]
+    #[tokio::test]
+    async fn test_verify_manifest() {
+        use crate::fileutil::TempPath;
+        let file = TempPath::new(MANIFEST_NAME);
+        let file_name = file.str();
+        let manifest = Manifest::new(
+            "demo",
+            Manifest::parse("demo", format!("{},3,a.txt", D1).as_bytes())
+                .unwrap()
+                .files,
+        );
+        fs::write(file_name, manifest.to_json().unwrap())
+            .await
+            .unwrap();
+        assert!(verify_manifest(file_name).await.is_ok());
+        fs::write(file_name, format!("{},3,a.txt\r\n{}", D1, D2))
+            .await
+            .unwrap();
+        let error = verify_manifest(file_name).await.unwrap_err().to_string();
+        assert!(error.ends_with("line 2: missing field size"), "{}", error);
+    }
+}
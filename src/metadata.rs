@@ -0,0 +1,183 @@
+use crate::fileutil::{check_inside, is_link_inside};
+use anyhow::{anyhow, Result};
+use std::fs::{self, Metadata};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+//What a written file gets back besides its content
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FileMeta {
+    //unix seconds
+    pub mtime: Option<u64>,
+    //permission bits, unix only
+    pub mode: Option<u32>,
+    //(uid,gid), unix only
+    pub owner: Option<(u32, u32)>,
+}
+
+impl FileMeta {
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map(|x| x.as_secs());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            FileMeta {
+                mtime,
+                mode: Some(meta.mode() & 0o7777),
+                owner: Some((meta.uid(), meta.gid())),
+            }
+        }
+        #[cfg(not(unix))]
+        FileMeta {
+            mtime,
+            mode: None,
+            owner: None,
+        }
+    }
+}
+
+//Set what differs from meta on file_name, the owner only with preserve_owner as it takes root.
+//Blocking, a caller with many files runs it through spawn_blocking
+pub fn restore_metadata(file_name: &str, meta: FileMeta, preserve_owner: bool) -> Result<()> {
+    let current = FileMeta::from_metadata(&fs::metadata(file_name)?);
+    //before the mode, which may take away the right to open the file
+    if let Some(mtime) = meta.mtime.filter(|x| current.mtime != Some(*x)) {
+        set_mtime(file_name, mtime)?;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let owner = meta
+            .owner
+            .filter(|x| preserve_owner && current.owner != Some(*x));
+        if let Some((uid, gid)) = owner {
+            std::os::unix::fs::chown(file_name, Some(uid), Some(gid))?;
+        }
+        //chown clears the setuid bits, so the mode goes after it
+        if let Some(mode) = meta
+            .mode
+            .filter(|x| owner.is_some() || current.mode != Some(*x))
+        {
+            fs::set_permissions(file_name, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+fn set_mtime(file_name: &str, mtime: u64) -> Result<()> {
+    //the owner may set times through a read-only handle on unix
+    #[cfg(unix)]
+    let file = fs::File::open(file_name)?;
+    #[cfg(not(unix))]
+    let file = fs::OpenOptions::new().write(true).open(file_name)?;
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+    Ok(())
+}
+
+//Make link, relative to the catalog root path, a symlink to target, replacing the file or link there,
+//return false when it already was that link. A target leading outside of path is refused
+pub fn restore_link(path: &str, link: &str, target: &str) -> Result<bool> {
+    let link_name = String::from(path) + "/" + link;
+    if !is_link_inside(link, target) {
+        return Err(anyhow!("{} -> {} leads outside of {}", link, target, path));
+    }
+    check_inside(path, &link_name)?;
+    if let Ok(current) = fs::read_link(&link_name) {
+        if current == Path::new(target) {
+            return Ok(false);
+        }
+    }
+    match fs::symlink_metadata(&link_name) {
+        //never remove a whole directory for a link
+        Ok(meta) if meta.is_dir() => {
+            return Err(anyhow!("{} is a directory", link_name));
+        }
+        //a link to a directory is removed as a directory on Windows
+        Ok(_) => fs::remove_file(&link_name).or_else(|_| fs::remove_dir(&link_name))?,
+        Err(_) => {}
+    }
+    if let Some(folder) = Path::new(&link_name).parent() {
+        fs::create_dir_all(folder)?;
+    }
+    symlink(target, &link_name)?;
+    //".." after another link climbs from where that one points
+    let root = fs::canonicalize(path)?;
+    if fs::canonicalize(&link_name).is_ok_and(|x| !x.starts_with(&root)) {
+        let _ = fs::remove_file(&link_name).or_else(|_| fs::remove_dir(&link_name));
+        return Err(anyhow!("{} -> {} leads outside of {}", link, target, path));
+    }
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, link_name: &str) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link_name)
+}
+
+//Windows tells links to files and to directories apart, which needs the target
+#[cfg(windows)]
+fn symlink(target: &str, link_name: &str) -> std::io::Result<()> {
+    let folder = Path::new(link_name).parent().unwrap_or(Path::new(""));
+    if folder.join(target).is_dir() {
+        std::os::windows::fs::symlink_dir(target, link_name)
+    } else {
+        std::os::windows::fs::symlink_file(target, link_name)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_target: &str, _link_name: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "symlinks are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileutil::TempPath;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_metadata_and_link() {
+        let temp = TempPath::new("metadata");
+        let dir = temp.path();
+        fs::create_dir_all(dir).unwrap();
+        let file_name = dir.join("app.sh").to_str().unwrap().to_string();
+        fs::write(&file_name, "echo").unwrap();
+        let meta = FileMeta {
+            mtime: Some(1_000_000_000),
+            mode: Some(0o750),
+            owner: Some((54321, 54321)),
+        };
+        //the owner is left alone unless asked for
+        restore_metadata(&file_name, meta, false).unwrap();
+        let restored = FileMeta::from_metadata(&fs::metadata(&file_name).unwrap());
+        assert_eq!(restored.mtime, meta.mtime);
+        assert_eq!(restored.mode, meta.mode);
+        assert_ne!(restored.owner, meta.owner);
+
+        let path = dir.to_str().unwrap();
+        let link_name = dir.join("sub/current");
+        assert!(restore_link(path, "sub/current", "../app.sh").unwrap());
+        assert!(!restore_link(path, "sub/current", "../app.sh").unwrap());
+        assert_eq!(fs::read_to_string(&link_name).unwrap(), "echo");
+        //a file in the way is replaced, a directory is not
+        assert!(restore_link(path, "app.sh", "sub").unwrap());
+        assert_eq!(fs::read_link(&file_name).unwrap(), Path::new("sub"));
+        assert!(restore_link(path, "sub", "app.sh").is_err());
+        //nothing may point or be written outside of the catalog
+        assert!(restore_link(path, "etc", "/etc").is_err());
+        assert!(restore_link(path, "sub/up", "../..").is_err());
+        assert!(restore_link(path, "root", ".").unwrap());
+        assert!(restore_link(path, "up", "root/..").is_err());
+        assert!(fs::symlink_metadata(dir.join("up")).is_err());
+        std::os::unix::fs::symlink("/etc", dir.join("etc")).unwrap();
+        assert!(restore_link(path, "etc/cron.d/x", "../x").is_err());
+    }
+}
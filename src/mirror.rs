@@ -0,0 +1,250 @@
+use crate::download::{get_manifest, DownloadContext};
+use crate::filter::FileFilter;
+use crate::fileutil::write_file_atomic;
+use crate::manifest::{load_manifest, Manifest, LEGACY_LIST_NAME, MANIFEST_NAME};
+use crate::json_helper::JsonHelper;
+use anyhow::Result;
+use byte_unit::Byte;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::path::{Component, Path};
+use tokio::fs::{self, DirBuilder};
+
+//Never pruned, whatever the lists say
+const DEFAULT_PROTECTED: [&str; 5] = [
+    "filer.json",
+    "filer.exe",
+    "filer",
+    "filelist.txt",
+    "filelist.json",
+];
+
+//Protected entries match either the whole relative path or the bare file name, case-insensitively
+fn is_protected(file_name: &str, protected: &[String]) -> bool {
+    let file_name = file_name.to_lowercase();
+    let base_name = file_name.rsplit('/').next().unwrap_or("");
+    DEFAULT_PROTECTED
+        .iter()
+        .map(|x| x.to_string())
+        .chain(protected.iter().map(|x| x.to_lowercase()))
+        .any(|x| x == file_name || x == base_name)
+}
+
+//Files in the local list which are gone from the remote list, return [(file_name,size)...]
+pub fn orphan_files<'a>(
+    local_file_list: &[(&'a str, u64, &'a str)],
+    remote_file_list: &[(&str, u64, &str)],
+    protected: &[String],
+) -> Vec<(&'a str, u64)> {
+    let remote_names = remote_file_list
+        .iter()
+        .map(|x| x.2)
+        .collect::<HashSet<&str>>();
+    local_file_list
+        .iter()
+        .filter(|x| !x.2.is_empty() && !remote_names.contains(x.2))
+        .filter(|x| !is_protected(x.2, protected))
+        .map(|x| (x.2, x.1))
+        .collect()
+}
+
+//Protected names from client.protected: ["app.log",...]
+pub fn protected_files(client_config: &Value) -> Vec<String> {
+    client_config["protected"]
+        .as_array()
+        .map(|x| {
+            x.iter()
+                .filter_map(|x| x.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+//Delete orphans under path, or move them into client.trash_path when configured.
+//return (pruned_count,pruned_size)
+pub async fn remove_orphan_files(
+    client_config: &Value,
+    path: &str,
+    orphans: &[(&str, u64)],
+    dry_run: bool,
+) -> Result<(usize, u64)> {
+    let trash_path = client_config["trash_path"].str("");
+    let mut pruned_count: usize = 0;
+    let mut pruned_size: u64 = 0;
+    for (file_name, file_size) in orphans {
+        //never follow a name out of path
+        let is_relative = Path::new(file_name)
+            .components()
+            .all(|x| matches!(x, Component::Normal(_) | Component::CurDir));
+        let local_file_name = String::from(path) + "/" + file_name;
+        if !is_relative || fs::symlink_metadata(&local_file_name).await.is_err() {
+            continue;
+        }
+        let action = if trash_path.is_empty() {
+            "Delete".to_string()
+        } else {
+            format!("Move to {}", trash_path)
+        };
+        println!(
+            ">>{} {} {}{}",
+            action,
+            file_name,
+            Byte::from_bytes(*file_size as u128).get_appropriate_unit(false),
+            if dry_run { " (dry run)" } else { "" }
+        );
+        if dry_run {
+            pruned_count += 1;
+            pruned_size += file_size;
+            continue;
+        }
+        let result = if trash_path.is_empty() {
+            fs::remove_file(&local_file_name).await
+        } else {
+            let trash_file_name = String::from(trash_path) + "/" + file_name;
+            if let Some(folder) = Path::new(&trash_file_name).parent() {
+                DirBuilder::new().recursive(true).create(folder).await?;
+            }
+            fs::rename(&local_file_name, &trash_file_name).await
+        };
+        match result {
+            Ok(_) => {
+                pruned_count += 1;
+                pruned_size += file_size;
+                remove_empty_folders(path, file_name).await;
+            }
+            Err(e) => println!(">>{} {} fail: {:?}", action, file_name, e),
+        }
+    }
+    Ok((pruned_count, pruned_size))
+}
+
+//Remove the folders of file_name left empty, up to but not including path
+async fn remove_empty_folders(path: &str, file_name: &str) {
+    let mut folder = Path::new(file_name).parent();
+    while let Some(relative) = folder {
+        if relative.as_os_str().is_empty() {
+            break;
+        }
+        let full_name = Path::new(path).join(relative);
+        if fs::remove_dir(&full_name).await.is_err() {
+            break;
+        }
+        folder = relative.parent();
+    }
+}
+
+//Drop the orphans gone from path out of the local lists and write them again,
+//an orphan which could not be removed stays listed
+async fn write_pruned_lists(path: &str, manifest: &Manifest, orphans: &[(&str, u64)]) -> Result<()> {
+    let mut pruned: HashSet<&str> = HashSet::new();
+    for (file_name, _) in orphans {
+        if fs::symlink_metadata(String::from(path) + "/" + file_name).await.is_err() {
+            pruned.insert(file_name);
+        }
+    }
+    if pruned.is_empty() {
+        return Ok(());
+    }
+    let mut manifest = manifest.clone();
+    manifest.files.retain(|x| !pruned.contains(x.path.as_str()));
+    let file_name = String::from(path) + "/" + MANIFEST_NAME;
+    write_file_atomic(&file_name, &manifest.to_json()?).await?;
+    let file_name = String::from(path) + "/" + LEGACY_LIST_NAME;
+    write_file_atomic(&file_name, manifest.to_legacy().as_bytes()).await?;
+    Ok(())
+}
+
+//filer prune: compare the local manifest with the server and remove what is gone from the server
+pub async fn prune_files(config: &Value, catalog: &str, dry_run: bool) -> Result<()> {
+    let client_config = &config["client"];
+    let catalog = client_config["catalog"].string(catalog);
+    let context = DownloadContext::new(client_config, &catalog).await?;
+    let path = client_config["path"].str("./demo_sent");
+    let public_key = client_config["public_key"].str("");
+    let remote_manifest = get_manifest(&context, public_key).await?;
+    let remote_file_list = remote_manifest.entry_list();
+    let local_manifest = load_manifest(path, &catalog, LEGACY_LIST_NAME)
+        .await
+        .unwrap_or_else(|_| Manifest::new(&catalog, Vec::new()));
+    //files left out by include/exclude rules are not synced, so never pruned either
+    let filter = FileFilter::load(&config[&catalog], path).await?;
+    let local_file_list: Vec<(&str, u64, &str)> = local_manifest
+        .entry_list()
+        .into_iter()
+        .filter(|x| filter.is_included(x.2))
+        .collect();
+    let orphans = orphan_files(
+        &local_file_list,
+        &remote_file_list,
+        &protected_files(client_config),
+    );
+    let (pruned_count, pruned_size) =
+        remove_orphan_files(client_config, path, &orphans, dry_run).await?;
+    //the next update must not count the pruned files as local
+    if !dry_run {
+        write_pruned_lists(path, &local_manifest, &orphans).await?;
+    }
+    println!(
+        "Prune {} files with size {} removed from {} on the server{}.",
+        pruned_count,
+        Byte::from_bytes(pruned_size as u128).get_appropriate_unit(false),
+        catalog,
+        if dry_run { " (dry run)" } else { "" }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orphan_files() {
+        let local = vec![
+            ("d1", 1, "keep.txt"),
+            ("d2", 2, "gone.txt"),
+            ("d3", 3, "sub/gone.log"),
+            ("d4", 4, "sub/Filer.json"),
+            ("d5", 5, "data/app.db"),
+        ];
+        let remote = vec![("d1", 1, "keep.txt")];
+        let protected = vec!["data/APP.db".to_string()];
+        assert_eq!(
+            orphan_files(&local, &remote, &protected),
+            vec![("gone.txt", 2), ("sub/gone.log", 3)]
+        );
+        assert_eq!(orphan_files(&local, &local, &[]), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_write_pruned_lists() {
+        use crate::fileutil::TempPath;
+        use crate::manifest::ManifestEntry;
+        let root = TempPath::new("prune");
+        let path = root.str();
+        std::fs::create_dir_all(path).unwrap();
+        std::fs::write(root.path().join("kept.txt"), b"kept").unwrap();
+        let entry = |name: &str| ManifestEntry {
+            path: name.to_string(),
+            size: 4,
+            digest: blake3::hash(b"kept").to_hex().to_string(),
+            mtime: None,
+            mode: None,
+            uid: None,
+            gid: None,
+            link: None,
+            chunks: Vec::new(),
+        };
+        let manifest = Manifest::new("demo", vec![entry("kept.txt"), entry("gone.txt"), entry("keep.txt")]);
+        //kept.txt could not be removed, gone.txt was
+        write_pruned_lists(path, &manifest, &[("kept.txt", 4), ("gone.txt", 4)])
+            .await
+            .unwrap();
+        let written = load_manifest(path, "demo", LEGACY_LIST_NAME).await.unwrap();
+        let names: Vec<&str> = written.files.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(names, vec!["kept.txt", "keep.txt"]);
+        let legacy = std::fs::read_to_string(root.path().join(LEGACY_LIST_NAME)).unwrap();
+        assert!(!legacy.contains("gone.txt"));
+        assert!(legacy.contains("keep.txt"));
+    }
+}
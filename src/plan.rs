@@ -0,0 +1,294 @@
+use crate::fileutil::{is_catalog_binary, NEW_BINARY_SUFFIX};
+use crate::manifest::ManifestChunk;
+use byte_unit::Byte;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+//(digest,size,name) as parsed from filelist.txt
+pub type FileEntry<'a> = (&'a str, u64, &'a str);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct PlanFile<'a> {
+    pub file: &'a str,
+    pub size: u64,
+    pub digest: &'a str,
+}
+impl<'a> From<FileEntry<'a>> for PlanFile<'a> {
+    fn from((digest, size, file): FileEntry<'a>) -> Self {
+        PlanFile { file, size, digest }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct LocalCopy<'a> {
+    pub file: &'a str,
+    pub size: u64,
+    pub digest: &'a str,
+    //file of the same digest fetched earlier in the same run
+    pub source: &'a str,
+}
+
+//What a download/update run is going to do, computed without touching the disk
+#[derive(Serialize, Default, Debug)]
+pub struct SyncPlan<'a> {
+    pub catalog: String,
+    pub download_all: bool,
+    //not in the local list
+    pub new: Vec<PlanFile<'a>>,
+    //in the local list with another digest or size
+    pub changed: Vec<PlanFile<'a>>,
+    //same as the local list, downloaded again only with download_all
+    pub identical: Vec<PlanFile<'a>>,
+    //finished by an interrupted run
+    pub resumed: Vec<PlanFile<'a>>,
+    //files to write which are fetched over the network, in order
+    pub fetch: Vec<PlanFile<'a>>,
+    //files to write which are copied from a fetched duplicate
+    pub local_copies: Vec<LocalCopy<'a>>,
+    //mirror mode only, gone from the server
+    pub removed: Vec<PlanFile<'a>>,
+    //bytes of the fetched files not covered by reused chunks
+    pub network_size: u64,
+    //bytes of the fetched files built from chunks already on disk
+    pub reused_chunk_size: u64,
+    pub local_copy_size: u64,
+}
+
+//Client side files which are never synced from the server
+pub fn is_excluded(file_name: &str) -> bool {
+    let file_name = file_name.to_lowercase();
+    let staged_binary = file_name
+        .strip_suffix(NEW_BINARY_SUFFIX)
+        .is_some_and(is_catalog_binary);
+    file_name.ends_with("filer.json") || staged_binary
+}
+
+pub fn sync_plan<'a>(
+    catalog: &str,
+    download_all: bool,
+    remote_file_list: &[FileEntry<'a>],
+    local_file_list: &[FileEntry<'a>],
+    run_journal: &[FileEntry<'a>],
+    removed: Vec<(&'a str, u64)>,
+) -> SyncPlan<'a> {
+    //(file_name,(digest,file_size))
+    let local_file_list: HashMap<&str, (&str, u64)> = local_file_list
+        .iter()
+        .filter(|x| !x.2.is_empty())
+        .map(|x| (x.2, (x.0, x.1)))
+        .collect();
+    let run_journal: HashMap<&str, (&str, u64)> =
+        run_journal.iter().map(|x| (x.2, (x.0, x.1))).collect();
+    let mut plan = SyncPlan {
+        catalog: catalog.to_string(),
+        download_all,
+        removed: removed
+            .into_iter()
+            .map(|(file, size)| PlanFile {
+                file,
+                size,
+                digest: "",
+            })
+            .collect(),
+        ..Default::default()
+    };
+    //digest -> first file of that digest fetched in this run
+    let mut fetched: HashMap<&str, &str> = HashMap::new();
+    for entry in remote_file_list {
+        let (digest, file_size, file_name) = *entry;
+        if is_excluded(file_name) {
+            continue;
+        }
+        let file = PlanFile::from(*entry);
+        if run_journal.get(file_name) == Some(&(digest, file_size)) {
+            plan.resumed.push(file);
+            continue;
+        }
+        match local_file_list.get(file_name) {
+            None => plan.new.push(file),
+            Some(local) if *local != (digest, file_size) => plan.changed.push(file),
+            Some(_) => {
+                plan.identical.push(file);
+                if !download_all {
+                    continue;
+                }
+            }
+        }
+        if let Some(source) = fetched.get(digest) {
+            plan.local_copy_size += file_size;
+            plan.local_copies.push(LocalCopy {
+                file: file_name,
+                size: file_size,
+                digest,
+                source,
+            });
+        } else {
+            fetched.insert(digest, file_name);
+            plan.network_size += file_size;
+            plan.fetch.push(file);
+        }
+    }
+    plan
+}
+
+impl<'a> SyncPlan<'a> {
+    //Take the chunks found on disk off network_size, on_disk holds the chunk digests of the
+    //local list, every fetched file adds its own chunks for the files after it
+    pub fn reuse_chunks(
+        &mut self,
+        chunks_of: impl Fn(&str) -> &'a [ManifestChunk],
+        mut on_disk: HashSet<&'a str>,
+    ) {
+        for file in &self.fetch {
+            let chunks = chunks_of(file.file);
+            let reused = chunks
+                .iter()
+                .filter(|x| on_disk.contains(x.digest.as_str()))
+                .map(|x| x.size)
+                .sum::<u64>();
+            self.reused_chunk_size += reused;
+            self.network_size -= reused.min(self.network_size);
+            on_disk.extend(chunks.iter().map(|x| x.digest.as_str()));
+        }
+    }
+
+    pub fn print(&self, json: bool) -> serde_json::Result<()> {
+        if json {
+            println!("{}", serde_json::to_string_pretty(self)?);
+            return Ok(());
+        }
+        fn size(size: u64) -> String {
+            format!(
+                "{}",
+                Byte::from_bytes(size as u128).get_appropriate_unit(false)
+            )
+        }
+        fn sum(files: &[PlanFile]) -> u64 {
+            files.iter().map(|x| x.size).sum()
+        }
+        println!(
+            "Plan for {} ({}):",
+            self.catalog,
+            if self.download_all {
+                "download"
+            } else {
+                "update"
+            }
+        );
+        for (mark, files) in [("+", &self.new), ("~", &self.changed), ("-", &self.removed)] {
+            for file in files.iter() {
+                println!(">>{} {} {}", mark, file.file, size(file.size));
+            }
+        }
+        if self.download_all {
+            for file in &self.identical {
+                println!(">>= {} {}", file.file, size(file.size));
+            }
+        }
+        for copy in &self.local_copies {
+            println!(
+                ">>c {} {} copied from {}",
+                copy.file,
+                size(copy.size),
+                copy.source
+            );
+        }
+        println!(
+            "New {} files {}, changed {} files {}, identical {} files {}, resumed {} files {}",
+            self.new.len(),
+            size(sum(&self.new)),
+            self.changed.len(),
+            size(sum(&self.changed)),
+            self.identical.len(),
+            size(sum(&self.identical)),
+            self.resumed.len(),
+            size(sum(&self.resumed)),
+        );
+        if !self.removed.is_empty() {
+            println!(
+                "Remove {} files {}",
+                self.removed.len(),
+                size(sum(&self.removed))
+            );
+        }
+        println!(
+            "Fetch {} files {} over the network and {} from chunks on disk, copy {} duplicate files {} locally",
+            self.fetch.len(),
+            size(self.network_size),
+            size(self.reused_chunk_size),
+            self.local_copies.len(),
+            size(self.local_copy_size),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_plan() {
+        let remote = vec![
+            ("d1", 1, "same.txt"),
+            ("d2", 2, "changed.txt"),
+            ("d3", 3, "new.txt"),
+            ("d3", 3, "sub/new_copy.txt"),
+            ("d4", 4, "resumed.txt"),
+            ("d5", 5, "filer.json"),
+        ];
+        let local = vec![("d1", 1, "same.txt"), ("d0", 2, "changed.txt")];
+        let run_journal = vec![("d4", 4, "resumed.txt")];
+        let plan = sync_plan(
+            "demo",
+            false,
+            &remote,
+            &local,
+            &run_journal,
+            vec![("old.txt", 9)],
+        );
+        fn names<'a>(files: &[PlanFile<'a>]) -> Vec<&'a str> {
+            files.iter().map(|x| x.file).collect()
+        }
+        assert_eq!(names(&plan.new), vec!["new.txt", "sub/new_copy.txt"]);
+        assert_eq!(names(&plan.changed), vec!["changed.txt"]);
+        assert_eq!(names(&plan.identical), vec!["same.txt"]);
+        assert_eq!(names(&plan.resumed), vec!["resumed.txt"]);
+        assert_eq!(names(&plan.removed), vec!["old.txt"]);
+        assert_eq!(names(&plan.fetch), vec!["changed.txt", "new.txt"]);
+        assert_eq!(plan.local_copies.len(), 1);
+        assert_eq!(plan.local_copies[0].source, "new.txt");
+        assert_eq!(plan.network_size, 5);
+        assert_eq!(plan.local_copy_size, 3);
+
+        let plan = sync_plan("demo", true, &remote, &local, &run_journal, vec![]);
+        assert_eq!(
+            names(&plan.fetch),
+            vec!["same.txt", "changed.txt", "new.txt"]
+        );
+        assert_eq!(plan.network_size, 6);
+    }
+
+    #[test]
+    fn test_reuse_chunks() {
+        let chunk = |size, digest: &str| ManifestChunk {
+            size,
+            digest: digest.to_string(),
+        };
+        let a = vec![chunk(4, "c1"), chunk(6, "c2")];
+        let b = vec![chunk(6, "c2"), chunk(5, "c3")];
+        let remote = vec![("da", 10, "a.bin"), ("db", 11, "b.bin"), ("dc", 3, "c.txt")];
+        let local = vec![("d0", 7, "a.bin")];
+        let mut plan = sync_plan("demo", false, &remote, &local, &[], vec![]);
+        assert_eq!(plan.network_size, 24);
+        let chunks_of = |file: &str| match file {
+            "a.bin" => a.as_slice(),
+            "b.bin" => b.as_slice(),
+            _ => &[],
+        };
+        //c1 is in the old a.bin, c2 is fetched with a.bin before b.bin
+        plan.reuse_chunks(chunks_of, HashSet::from(["c1"]));
+        assert_eq!(plan.reused_chunk_size, 10);
+        assert_eq!(plan.network_size, 14);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::fileutil::is_filer_binary;
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
+
+//How long a process may take to exit on its own before it is killed
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+pub struct RunningProcess {
+    pub pid: u32,
+    pub exe: PathBuf,
+}
+
+//Full path as the OS reports it for a running executable, so both sides compare equal
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let path = std::fs::canonicalize(path).ok()?;
+    if cfg!(windows) {
+        //without the verbatim prefix canonicalize adds, names are not case sensitive
+        let path = path.to_string_lossy();
+        let path = path.strip_prefix(r"\\?\").unwrap_or(&path);
+        return Some(PathBuf::from(path.to_lowercase()));
+    }
+    Some(path)
+}
+
+//Processes whose executable lives inside path, never this process
+pub async fn find_running_in(path: &str) -> Result<Vec<RunningProcess>> {
+    let path = normalize(Path::new(path)).ok_or_else(|| anyhow!("{} does not exist", path))?;
+    let own_pid = std::process::id();
+    Ok(list_processes()
+        .await?
+        .into_iter()
+        .filter(|x| x.pid != own_pid && x.exe.starts_with(&path))
+        .collect())
+}
+
+#[cfg(target_os = "linux")]
+async fn list_processes() -> Result<Vec<RunningProcess>> {
+    tokio::task::spawn_blocking(|| {
+        let mut processes = Vec::new();
+        for entry in std::fs::read_dir("/proc")?.flatten() {
+            let pid = match entry
+                .file_name()
+                .to_str()
+                .and_then(|x| x.parse::<u32>().ok())
+            {
+                Some(pid) => pid,
+                None => continue,
+            };
+            //processes of other users or already gone cannot be read, they are skipped
+            if let Ok(exe) = std::fs::read_link(entry.path().join("exe")) {
+                processes.push(RunningProcess { pid, exe });
+            }
+        }
+        Ok(processes)
+    })
+    .await?
+}
+
+#[cfg(windows)]
+async fn list_processes() -> Result<Vec<RunningProcess>> {
+    let output = Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            "Get-CimInstance Win32_Process | ForEach-Object { \"$($_.ProcessId)|$($_.ExecutablePath)\" }",
+        ])
+        .output()
+        .await?;
+    Ok(parse_process_list(&String::from_utf8_lossy(&output.stdout)))
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+async fn list_processes() -> Result<Vec<RunningProcess>> {
+    Err(anyhow!(
+        "listing processes is not supported on this platform"
+    ))
+}
+
+//Lines of pid|executable path, processes without a path are system processes
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_process_list(output: &str) -> Vec<RunningProcess> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (pid, exe) = line.trim().split_once('|')?;
+            if exe.is_empty() {
+                return None;
+            }
+            Some(RunningProcess {
+                pid: pid.parse().ok()?,
+                exe: PathBuf::from(exe.to_lowercase()),
+            })
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+async fn is_running(pid: u32) -> bool {
+    //a zombie has exited already, only its parent has not reaped it yet
+    match tokio::fs::read_to_string(format!("/proc/{}/stat", pid)).await {
+        Ok(stat) => {
+            stat.rsplit_once(')')
+                .map(|x| x.1.trim_start().starts_with('Z'))
+                == Some(false)
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(windows)]
+async fn is_running(pid: u32) -> bool {
+    let filter = format!("PID eq {}", pid);
+    match Command::new("tasklist")
+        .args(["/FI", &filter, "/NH"])
+        .output()
+        .await
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .any(|x| x == pid.to_string()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+async fn is_running(_pid: u32) -> bool {
+    false
+}
+
+async fn signal(pid: u32, force: bool) -> Result<()> {
+    let pid = pid.to_string();
+    #[cfg(windows)]
+    let mut command = {
+        //without /F taskkill asks the windows of the process to close
+        let mut command = Command::new("taskkill");
+        command.args(["/PID", &pid, "/T"]);
+        if force {
+            command.arg("/F");
+        }
+        command
+    };
+    #[cfg(not(windows))]
+    let mut command = {
+        let mut command = Command::new("kill");
+        command.args([if force { "-KILL" } else { "-TERM" }, &pid]);
+        command
+    };
+    command
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await?;
+    Ok(())
+}
+
+//Ask the process to exit, kill it when it is still running after the grace period,
+//return whether it had to be killed
+pub async fn stop_process(pid: u32) -> Result<bool> {
+    signal(pid, false).await?;
+    let deadline = tokio::time::Instant::now() + GRACE_PERIOD;
+    while tokio::time::Instant::now() < deadline {
+        if !is_running(pid).await {
+            return Ok(false);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    signal(pid, true).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    if is_running(pid).await {
+        Err(anyhow!("process {} is still running", pid))
+    } else {
+        Ok(true)
+    }
+}
+
+//Stop the processes running one of files under path, they would keep the old copy
+//open or block replacing it
+//Filer binaries are left alone, a new one is staged aside and never overwrites a running one
+pub async fn stop_running_files(path: &str, files: &[&str]) {
+    let targets: HashSet<PathBuf> = files
+        .iter()
+        .filter(|x| !is_filer_binary(path, x))
+        .filter_map(|x| normalize(&Path::new(path).join(x)))
+        .collect();
+    //nothing to replace on disk yet, so nothing of it can be running
+    if targets.is_empty() {
+        return;
+    }
+    let processes = match find_running_in(path).await {
+        Ok(processes) => processes,
+        Err(e) => {
+            println!("Skip stopping running programs: {}", e);
+            return;
+        }
+    };
+    for process in processes.iter().filter(|x| targets.contains(&x.exe)) {
+        print!(
+            "Stop running {} ({}).. ",
+            process.exe.display(),
+            process.pid
+        );
+        match stop_process(process.pid).await {
+            Ok(false) => println!("exited"),
+            Ok(true) => println!("killed"),
+            Err(e) => println!("失败: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileutil::TempPath;
+
+    #[test]
+    fn test_parse_process_list() {
+        let output = "4|\r\n1200|C:\\Apps\\Demo\\App.exe\r\nnot a process\r\n1300|C:\\Windows\\explorer.exe\r\n";
+        assert_eq!(
+            parse_process_list(output),
+            vec![
+                RunningProcess {
+                    pid: 1200,
+                    exe: PathBuf::from("c:\\apps\\demo\\app.exe")
+                },
+                RunningProcess {
+                    pid: 1300,
+                    exe: PathBuf::from("c:\\windows\\explorer.exe")
+                },
+            ]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_stop_running_files() {
+        let temp = TempPath::new("running");
+        let dir = temp.path();
+        std::fs::create_dir_all(dir).unwrap();
+        let exe = dir.join("sleeper");
+        std::fs::copy("/bin/sleep", &exe).unwrap();
+        let mut child = std::process::Command::new(&exe).arg("30").spawn().unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let path = dir.to_str().unwrap();
+        let running = find_running_in(path).await.unwrap();
+        assert_eq!(
+            running.iter().map(|x| x.pid).collect::<Vec<u32>>(),
+            vec![child.id()]
+        );
+        //only the files being replaced are stopped
+        stop_running_files(path, &["other"]).await;
+        assert!(is_running(child.id()).await);
+        stop_running_files(path, &["sleeper"]).await;
+        child.wait().unwrap();
+        assert!(find_running_in(path).await.unwrap().is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_stop_running_files_skips_filer() {
+        let temp = TempPath::new("running-filer");
+        let dir = temp.path();
+        std::fs::create_dir_all(dir).unwrap();
+        let exe = dir.join("filer");
+        std::fs::copy("/bin/sleep", &exe).unwrap();
+        let mut child = std::process::Command::new(&exe).arg("30").spawn().unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let path = dir.to_str().unwrap();
+        stop_running_files(path, &["filer"]).await;
+        assert!(is_running(child.id()).await);
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{anyhow, Result};
+use byte_unit::Byte;
+use chrono::{Datelike, Local, NaiveTime, Weekday};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+//A rate in bytes per second, 1048576 or "1 MiB", 0 is unlimited
+fn parse_rate(value: &Value) -> Result<u64> {
+    match value {
+        Value::Number(rate) => rate
+            .as_u64()
+            .ok_or_else(|| anyhow!("invalid rate {}", rate)),
+        Value::String(rate) => Byte::from_str(rate)
+            .map(|x| x.get_bytes() as u64)
+            .map_err(|e| anyhow!("invalid rate {:?}: {}", rate, e)),
+        _ => Err(anyhow!("invalid rate {}", value)),
+    }
+}
+
+fn parse_time(value: &Value) -> Result<NaiveTime> {
+    let time = value.as_str().unwrap_or("");
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| anyhow!("invalid time {:?}, expect HH:MM", time))
+}
+
+#[derive(Clone, Debug)]
+struct Window {
+    //every day when empty
+    days: Vec<Weekday>,
+    from: NaiveTime,
+    //a window ending before it starts runs over midnight
+    to: NaiveTime,
+    rate: u64,
+}
+
+impl Window {
+    fn contains(&self, day: Weekday, time: NaiveTime) -> bool {
+        let in_time = if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        };
+        in_time && (self.days.is_empty() || self.days.contains(&day))
+    }
+}
+
+//Config "rate_limit": "20 MiB", or with office hours
+//{"rate": "50 MiB", "schedule": [{"from": "08:00", "to": "18:00", "days": ["Mon","Fri"], "rate": "5 MiB"}]}
+//the first matching window wins, "rate" applies outside of every window
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    rate: u64,
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    //None when the key is not set
+    pub fn from_config(value: &Value) -> Result<Option<Self>> {
+        if value.is_null() {
+            return Ok(None);
+        }
+        if !value.is_object() {
+            let rate = parse_rate(value)?;
+            return Ok(Some(Schedule {
+                rate,
+                windows: Vec::new(),
+            }));
+        }
+        let rate = match &value["rate"] {
+            Value::Null => 0,
+            rate => parse_rate(rate)?,
+        };
+        let mut windows = Vec::new();
+        for window in value["schedule"].as_array().into_iter().flatten() {
+            let days = window["days"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|x| {
+                    let day = x.as_str().unwrap_or("");
+                    day.parse::<Weekday>()
+                        .map_err(|_| anyhow!("invalid day {:?}", day))
+                })
+                .collect::<Result<Vec<Weekday>>>()?;
+            windows.push(Window {
+                days,
+                from: parse_time(&window["from"])?,
+                to: parse_time(&window["to"])?,
+                rate: parse_rate(&window["rate"])?,
+            });
+        }
+        Ok(Some(Schedule { rate, windows }))
+    }
+
+    fn rate_at(&self, day: Weekday, time: NaiveTime) -> u64 {
+        self.windows
+            .iter()
+            .find(|x| x.contains(day, time))
+            .map_or(self.rate, |x| x.rate)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    //Refill up to one second of rate, take bytes on credit,
+    //return how long the caller waits until the debt is paid back
+    fn take(&mut self, rate: u64, bytes: u64, now: Instant) -> Duration {
+        let rate = rate as f64;
+        let refill = now.saturating_duration_since(self.last).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate) - bytes as f64;
+        self.last = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+//Token bucket following a schedule, shared by every transfer it limits
+pub struct RateLimiter {
+    schedule: Schedule,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: Schedule) -> Self {
+        RateLimiter {
+            schedule,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn from_config(value: &Value) -> Result<Option<Self>> {
+        Ok(Schedule::from_config(value)?.map(RateLimiter::new))
+    }
+
+    //wait until bytes may be sent or received
+    pub async fn acquire(&self, bytes: u64) {
+        let now = Local::now();
+        let rate = self.schedule.rate_at(now.weekday(), now.time());
+        if rate == 0 {
+            return;
+        }
+        let wait = match self.bucket.lock() {
+            Ok(mut bucket) => bucket.take(rate, bytes, Instant::now()),
+            Err(_) => Duration::ZERO,
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_schedule() {
+        let time = |x: &str| NaiveTime::parse_from_str(x, "%H:%M").unwrap();
+        assert!(Schedule::from_config(&Value::Null).unwrap().is_none());
+        let always = Schedule::from_config(&json!("2 KiB")).unwrap().unwrap();
+        assert_eq!(always.rate_at(Weekday::Sun, time("03:00")), 2048);
+
+        let office = Schedule::from_config(&json!({
+            "schedule": [
+                {"from": "08:00", "to": "18:00", "days": ["Mon", "Tue", "Wed", "Thu", "Fri"], "rate": 1000},
+                {"from": "22:00", "to": "06:00", "rate": "1 MB"}
+            ]
+        }))
+        .unwrap()
+        .unwrap();
+        assert_eq!(office.rate_at(Weekday::Mon, time("08:00")), 1000);
+        assert_eq!(office.rate_at(Weekday::Fri, time("17:59")), 1000);
+        assert_eq!(office.rate_at(Weekday::Fri, time("18:00")), 0);
+        assert_eq!(office.rate_at(Weekday::Sat, time("10:00")), 0);
+        //over midnight
+        assert_eq!(office.rate_at(Weekday::Sat, time("23:00")), 1_000_000);
+        assert_eq!(office.rate_at(Weekday::Sun, time("05:59")), 1_000_000);
+
+        for invalid in [
+            json!("fast"),
+            json!({"rate": -1}),
+            json!({"schedule": [{"from": "8am", "to": "18:00", "rate": 1}]}),
+            json!({"schedule": [{"from": "08:00", "to": "18:00", "days": ["Someday"], "rate": 1}]}),
+        ] {
+            assert!(Schedule::from_config(&invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            last: start,
+        };
+        //1000 bytes per second, a burst of 500 bytes waits half a second
+        assert_eq!(bucket.take(1000, 500, start), Duration::from_millis(500));
+        //paid back after half a second
+        let now = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(1000, 0, now), Duration::ZERO);
+        //idle time saves up at most one second of rate
+        let now = now + Duration::from_secs(10);
+        assert_eq!(bucket.take(1000, 1000, now), Duration::ZERO);
+        assert_eq!(bucket.take(1000, 250, now), Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,104 @@
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::{self, JoinHandle};
+
+//Shared by every file of a run instead of batch waves: at most max_tasks parts are
+//transferred at any moment whichever files they belong to, and a file starts as soon
+//as another one finishes, so one slow large file no longer holds up the rest
+#[derive(Clone)]
+pub struct Scheduler {
+    parts: Arc<Semaphore>,
+    //files only wait on parts, never the other way round, so the two bounds cannot deadlock
+    files: Arc<Semaphore>,
+}
+
+impl Scheduler {
+    pub fn new(max_tasks: u64) -> Self {
+        //far below the permit limit a tokio semaphore panics at
+        let max_tasks = max_tasks.clamp(1, 1 << 20) as usize;
+        Scheduler {
+            parts: Arc::new(Semaphore::new(max_tasks)),
+            files: Arc::new(Semaphore::new(max_tasks)),
+        }
+    }
+
+    //Run a part once a slot is free, the slot is released as soon as it is done
+    pub async fn run<F: Future>(&self, part: F) -> F::Output {
+        let _permit = self.parts.acquire().await;
+        part.await
+    }
+
+    pub fn spawn<F>(&self, part: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let scheduler = self.clone();
+        task::spawn(async move { scheduler.run(part).await })
+    }
+
+    //Queue a whole file, it may start parts of its own through run and spawn
+    //files start as others finish, results are reported in the order they complete
+    pub fn spawn_file<F>(&self, file: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let files = self.files.clone();
+        task::spawn(async move {
+            let _permit = files.acquire().await;
+            file.await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream::{FuturesUnordered, StreamExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_scheduler() {
+        let scheduler = Scheduler::new(3);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let part = |millis: u64| {
+            let running = running.clone();
+            let peak = peak.clone();
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(millis)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            }
+        };
+        //one slow file of one part, then quick files of four parts each
+        let mut files = FuturesUnordered::new();
+        for i in 0..6u64 {
+            let scheduler_ = scheduler.clone();
+            let parts: Vec<_> = if i == 0 {
+                vec![part(300)]
+            } else {
+                (0..4).map(|_| part(10)).collect()
+            };
+            files.push(scheduler.spawn_file(async move {
+                let parts: Vec<_> = parts.into_iter().map(|x| scheduler_.spawn(x)).collect();
+                for part in parts {
+                    part.await.unwrap();
+                }
+                i
+            }));
+        }
+        let mut done = Vec::new();
+        while let Some(file) = files.next().await {
+            done.push(file.unwrap());
+        }
+        //the quick files finish while the slow one is still running
+        assert_eq!(done.len(), 6);
+        assert_eq!(done.last(), Some(&0));
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::fileutil::{is_running_binary, NEW_BINARY_SUFFIX};
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+use tokio::fs;
+use tokio::process::Command;
+
+//The replaced binary while the new one is checked
+const OLD_BINARY_SUFFIX: &str = ".old";
+//Set for the re-executed process, which must not update itself again
+const UPDATED_ENV: &str = "FILER_SELF_UPDATED";
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn file_digest(file_name: &str) -> Result<String> {
+    let bytes = fs::read(file_name).await?;
+    Ok(format!("{}", blake3::hash(&bytes).to_hex()))
+}
+
+//The binary must start and report its version
+async fn health_check(binary: &str) -> Result<()> {
+    let output = Command::new(binary)
+        .arg("--version")
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(HEALTH_CHECK_TIMEOUT, output)
+        .await
+        .map_err(|_| anyhow!("{} --version did not answer", binary))??;
+    if output.status.success() && !output.stdout.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} --version failed with {}",
+            binary,
+            output.status
+        ))
+    }
+}
+
+//Move the checked new binary over binary, the old one comes back when the new one fails to start
+async fn swap_binary(staged: &str, binary: &str) -> Result<()> {
+    let old = String::from(binary) + OLD_BINARY_SUFFIX;
+    let has_old = fs::metadata(binary).await.is_ok();
+    if has_old {
+        fs::rename(binary, &old).await?;
+    }
+    if let Err(e) = fs::rename(staged, binary).await {
+        if has_old {
+            fs::rename(&old, binary).await?;
+        }
+        return Err(e.into());
+    }
+    if let Err(e) = health_check(binary).await {
+        let _ = fs::remove_file(binary).await;
+        if has_old {
+            fs::rename(&old, binary).await?;
+        }
+        return Err(anyhow!("rolled back: {}", e));
+    }
+    if has_old {
+        let _ = fs::remove_file(&old).await;
+    }
+    Ok(())
+}
+
+//Keep the mode the staged file got from the manifest, only make sure it can run
+#[cfg(unix)]
+async fn make_executable(staged: &str) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(staged).await?.permissions().mode();
+    //whoever may read it may run it, unless some execute bit is already there
+    let mode = if mode & 0o111 == 0 {
+        mode | (mode & 0o444) >> 2
+    } else {
+        mode
+    };
+    fs::set_permissions(staged, std::fs::Permissions::from_mode(mode)).await?;
+    Ok(())
+}
+
+//Run again with the same arguments as the new binary, only returns on failure
+#[cfg(unix)]
+pub fn restart(binary: &str) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+    println!("Restart {}", binary);
+    let error = std::process::Command::new(binary)
+        .args(std::env::args_os().skip(1))
+        .env(UPDATED_ENV, crate::VERSION)
+        .exec();
+    Err(anyhow!("restart {} fail: {}", binary, error))
+}
+
+//The helper takes over once this process exits
+#[cfg(not(unix))]
+pub fn restart(_binary: &str) -> Result<()> {
+    Ok(())
+}
+
+//A running exe cannot be replaced on Windows, a detached helper waits for this process
+//to exit, then swaps, checks and rolls back like swap_binary
+#[cfg_attr(not(windows), allow(dead_code))]
+fn windows_helper_script(pid: u32, staged: &str, binary: &str) -> String {
+    let old = String::from(binary) + OLD_BINARY_SUFFIX;
+    [
+        "@echo off".to_string(),
+        ":wait".to_string(),
+        format!(
+            "tasklist /FI \"PID eq {pid}\" /NH | find \"{pid}\" >nul && (timeout /T 1 /NOBREAK >nul & goto wait)"
+        ),
+        format!("move /Y \"{binary}\" \"{old}\" >nul || exit /B 1"),
+        format!("move /Y \"{staged}\" \"{binary}\" >nul || (move /Y \"{old}\" \"{binary}\" >nul & exit /B 1)"),
+        format!(
+            "\"{binary}\" --version >nul 2>&1 || (del \"{binary}\" & move /Y \"{old}\" \"{binary}\" >nul & exit /B 1)"
+        ),
+        format!("del \"{old}\""),
+        "del \"%~f0\"".to_string(),
+    ]
+    .join("\r\n")
+}
+
+#[cfg(windows)]
+async fn start_windows_helper(staged: &str, binary: &str) -> Result<()> {
+    use std::os::windows::process::CommandExt;
+    const DETACHED_PROCESS: u32 = 0x00000008;
+    let script_name = String::from(binary) + ".update.cmd";
+    let script = windows_helper_script(std::process::id(), staged, binary);
+    fs::write(&script_name, script).await?;
+    std::process::Command::new("cmd")
+        .args(["/C", &script_name])
+        .creation_flags(DETACHED_PROCESS)
+        .spawn()?;
+    Ok(())
+}
+
+//Verify the staged binary against the digest it was listed with and swap it in,
+//return whether the running filer was replaced and should be restarted
+pub async fn install_binary(staged: &str, digest: &str) -> Result<bool> {
+    let binary = staged
+        .strip_suffix(NEW_BINARY_SUFFIX)
+        .ok_or_else(|| anyhow!("{} is not a staged binary", staged))?;
+    let staged_digest = file_digest(staged).await?;
+    if staged_digest != digest {
+        return Err(anyhow!("{} does not match {}", staged, digest));
+    }
+    //fetched again by a full download, there is nothing to swap or restart
+    if file_digest(binary).await.is_ok_and(|x| x == digest) {
+        fs::remove_file(staged).await?;
+        return Ok(false);
+    }
+    let running = is_running_binary(binary);
+    //the process restarted by an update is already the current binary
+    if running && std::env::var_os(UPDATED_ENV).is_some() {
+        println!("{} is already current, not updated again in the same run", binary);
+        fs::remove_file(staged).await?;
+        return Ok(false);
+    }
+    #[cfg(unix)]
+    make_executable(staged).await?;
+    #[cfg(windows)]
+    if running {
+        start_windows_helper(staged, binary).await?;
+        println!("{} is replaced as soon as this run exits", binary);
+        return Ok(false);
+    }
+    //check the new binary can start before the old one is touched
+    health_check(staged).await?;
+    swap_binary(staged, binary).await?;
+    println!("Updated {}", binary);
+    Ok(running)
+}
+
+//Install every staged binary of a finished run, [(staged file,digest)...],
+//return the failure count and the running binary to restart when it was replaced
+pub async fn install_binaries(staged: &[(String, String)]) -> (usize, Option<String>) {
+    let mut error_count = 0;
+    let mut restart = None;
+    for (staged, digest) in staged {
+        match install_binary(staged, digest).await {
+            Ok(true) => restart = staged.strip_suffix(NEW_BINARY_SUFFIX).map(String::from),
+            Ok(false) => {}
+            Err(e) => {
+                error_count += 1;
+                println!("Update {} fail: {}", staged, e);
+                let _ = fs::remove_file(staged).await;
+            }
+        }
+    }
+    (error_count, restart)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileutil::TempPath;
+
+    #[test]
+    fn test_windows_helper_script() {
+        let script = windows_helper_script(42, r"C:\filer\filer.exe.new", r"C:\filer\filer.exe");
+        assert!(script.contains("PID eq 42"));
+        assert!(script.contains(r#"move /Y "C:\filer\filer.exe.new" "C:\filer\filer.exe""#));
+        assert!(script.contains(r#"move /Y "C:\filer\filer.exe.old" "C:\filer\filer.exe""#));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_install_binary() {
+        let temp = TempPath::new("update");
+        let dir = temp.path();
+        std::fs::create_dir_all(dir).unwrap();
+        let binary = dir.join("filer").to_str().unwrap().to_string();
+        let staged = binary.clone() + NEW_BINARY_SUFFIX;
+        let script = |version: &str| format!("#!/bin/sh\n{}\n", version);
+        fs::write(&binary, script("echo filer 1")).await.unwrap();
+        make_executable(&binary).await.unwrap();
+
+        //a binary which fails its health check is rolled back
+        fs::write(&staged, script("exit 1")).await.unwrap();
+        make_executable(&staged).await.unwrap();
+        assert!(swap_binary(&staged, &binary).await.is_err());
+        assert_eq!(
+            fs::read_to_string(&binary).await.unwrap(),
+            script("echo filer 1")
+        );
+        assert!(fs::metadata(binary.clone() + OLD_BINARY_SUFFIX)
+            .await
+            .is_err());
+
+        //an unchanged binary is left in place
+        fs::write(&staged, script("echo filer 1")).await.unwrap();
+        let digest = file_digest(&staged).await.unwrap();
+        assert!(!install_binary(&staged, &digest).await.unwrap());
+        assert!(fs::metadata(&staged).await.is_err());
+
+        //a digest mismatch leaves everything as it is
+        fs::write(&staged, script("echo filer 2")).await.unwrap();
+        assert!(install_binary(&staged, "0").await.is_err());
+        let digest = file_digest(&staged).await.unwrap();
+        assert!(!install_binary(&staged, &digest).await.unwrap());
+        assert_eq!(
+            fs::read_to_string(&binary).await.unwrap(),
+            script("echo filer 2")
+        );
+        assert!(fs::metadata(&staged).await.is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Result};
+#[cfg(feature = "digest")]
+use ed25519_dalek::{Signer, SigningKey};
+#[cfg(feature = "download")]
+use ed25519_dalek::{Signature, VerifyingKey};
+#[cfg(feature = "digest")]
+use tokio::fs;
+
+//Detached signature next to the manifest, <manifest>.sig holding 64 bytes as hex
+pub const SIGNATURE_SUFFIX: &str = ".sig";
+
+#[cfg(feature = "digest")]
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str, what: &str) -> Result<[u8; N]> {
+    let hex = hex.trim();
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return Err(anyhow!("{} must be {} hex digits", what, N * 2));
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("{} must be {} hex digits", what, N * 2))?;
+    }
+    Ok(bytes)
+}
+
+//filer keygen: write a new secret key to key_file, return the public key clients pin
+#[cfg(feature = "digest")]
+pub async fn generate_key(key_file: &str) -> Result<String> {
+    use tokio::io::AsyncWriteExt;
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed).map_err(|e| anyhow!("no random source: {}", e))?;
+    let key = SigningKey::from_bytes(&seed);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(key_file)
+        .await
+        .map_err(|e| anyhow!("create {} fail: {}", key_file, e))?;
+    file.write_all(to_hex(&seed).as_bytes()).await?;
+    file.sync_all().await?;
+    Ok(public_key(&key))
+}
+
+#[cfg(feature = "digest")]
+pub async fn load_signing_key(key_file: &str) -> Result<SigningKey> {
+    let hex = fs::read_to_string(key_file)
+        .await
+        .map_err(|e| anyhow!("read signing key {} fail: {}", key_file, e))?;
+    Ok(SigningKey::from_bytes(&from_hex::<32>(&hex, "signing key")?))
+}
+
+#[cfg(feature = "digest")]
+pub fn public_key(key: &SigningKey) -> String {
+    to_hex(key.verifying_key().as_bytes())
+}
+
+#[cfg(feature = "digest")]
+pub fn sign(key: &SigningKey, bytes: &[u8]) -> String {
+    to_hex(&key.sign(bytes).to_bytes())
+}
+
+//Check a detached signature of bytes against the pinned public key
+#[cfg(feature = "download")]
+pub fn verify(public_key: &str, bytes: &[u8], signature: &str) -> Result<()> {
+    let public_key = VerifyingKey::from_bytes(&from_hex::<32>(public_key, "public key")?)
+        .map_err(|_| anyhow!("public key is not a valid ed25519 key"))?;
+    let signature = Signature::from_bytes(&from_hex::<64>(signature, "signature")?);
+    public_key
+        .verify_strict(bytes, &signature)
+        .map_err(|_| anyhow!("signature does not match the pinned public key"))
+}
+
+#[cfg(all(test, feature = "digest", feature = "download"))]
+mod tests {
+    use super::*;
+    use crate::fileutil::TempPath;
+
+    #[tokio::test]
+    async fn test_sign_and_verify() {
+        let key_path = TempPath::new("signing.key");
+        let key_file = key_path.str();
+        let public = generate_key(key_file).await.unwrap();
+        //never overwrite an existing key
+        assert!(generate_key(key_file).await.is_err());
+        let key = load_signing_key(key_file).await.unwrap();
+        assert_eq!(public_key(&key), public);
+
+        let signature = sign(&key, b"manifest");
+        assert!(verify(&public, b"manifest", &signature).is_ok());
+        assert!(verify(&public, b"manifest!", &signature).is_err());
+        let other = SigningKey::from_bytes(&[7u8; 32]);
+        assert!(verify(&public_key(&other), b"manifest", &signature).is_err());
+        assert!(verify(&public, b"manifest", "00").is_err());
+        assert!(verify("xyz", b"manifest", &signature).is_err());
+    }
+}
@@ -1,10 +1,18 @@
 use crate::fileutil::{
-    calc_parts, get_dir_file_size, get_file_size, get_full_of_file, get_part_of_file,
-    kill_running_exe, PartData, MAX_SPLIT_PARTS,
+    calc_parts, check_inside, get_dir_entries, get_file_size, get_files_size, get_full_of_file,
+    get_part_of_file, is_filer_binary, replace_with_temp, target_file_name, PartData,
+    MAX_SPLIT_PARTS, TEMP_SUFFIX,
 };
+use crate::filter::FileFilter;
 use crate::json_helper::JsonHelper;
+use crate::metadata::{restore_link, restore_metadata, FileMeta};
+use crate::process::stop_running_files;
+use crate::scheduler::Scheduler;
+use crate::self_update::{file_digest, install_binaries, restart};
 use anyhow::{anyhow, Result};
+use blake3::Hasher;
 use byte_unit::Byte;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use serde_json::Value;
 use std::io::SeekFrom;
 use std::path::Path;
@@ -20,65 +28,113 @@ async fn xcopy_file(
     file_name: &str,
     file_size: u64,
     part_size: u64,
+    preserve_owner: bool,
+    scheduler: &Scheduler,
 ) -> Result<XCopyReturn> {
     let source_file_name = source_path.to_string() + "/" + file_name;
-    let target_file_name = if file_name.to_lowercase().ends_with("filer.exe") {
-        String::from(target_path) + "/" + file_name + ".new"
-    } else {
-        String::from(target_path) + "/" + file_name
-    };
+    let target_file_name = target_file_name(target_path, file_name);
+    let temp_file_name = target_file_name.clone() + TEMP_SUFFIX;
     //println!("copy {} to {}", source_file_name, target_file_name);
     let (parts, part_size) = calc_parts(file_size, part_size, MAX_SPLIT_PARTS);
     let target_file_folder = Path::new(&target_file_name)
         .parent()
         .ok_or_else(||anyhow!("get target file folder fail"))?;
+    check_inside(target_path, &target_file_name)?;
     DirBuilder::new()
         .recursive(true)
         .create(target_file_folder)
         .await?;
-    let mut target = File::create(&target_file_name).await?;
+    let mut target = File::create(&temp_file_name).await?;
+    let mut digest_calc = Hasher::new();
     let mut file_size_calc: u64 = 0;
     async fn process_result(
         result: Result<PartData>,
         target: &mut File,
+        digest: &mut Hasher,
         file_size: &mut u64,
     ) -> Result<()> {
         let (skip, take, result) = result?;
         target.seek(SeekFrom::Start(skip)).await?;
         target.write_all(&result).await?;
+        digest.update(&result);
         *file_size += take;
         Ok(())
     }
-    if parts == 1 {
-        let result = get_full_of_file(&source_file_name).await;
-        process_result(result, &mut target, &mut file_size_calc).await?;
-    } else {
-        let mut results: Vec<task::JoinHandle<Result<PartData>>> =
-            Vec::with_capacity(parts as usize);
-        for i in 0..parts as usize {
-            let skip = i as u64 * part_size;
-            let take = part_size;
-            let source_file_name = source_file_name.clone();
-            results.push(task::spawn(async move {
-                get_part_of_file(&source_file_name, skip, take).await
-            }));
-        }
-        for result in results {
-            let result = result.await?;
-            process_result(result, &mut target, &mut file_size_calc).await?;
+    //parts are written in order, so the digest covers the file as written
+    let written: Result<()> = async {
+        if parts == 1 {
+            let result = scheduler.run(get_full_of_file(&source_file_name)).await;
+            process_result(result, &mut target, &mut digest_calc, &mut file_size_calc).await?;
+        } else {
+            let mut results: Vec<task::JoinHandle<Result<PartData>>> =
+                Vec::with_capacity(parts as usize);
+            for i in 0..parts as usize {
+                let skip = i as u64 * part_size;
+                let take = part_size;
+                let source_file_name = source_file_name.clone();
+                results.push(scheduler.spawn(async move {
+                    get_part_of_file(&source_file_name, skip, take).await
+                }));
+            }
+            for result in results {
+                let result = result.await?;
+                process_result(result, &mut target, &mut digest_calc, &mut file_size_calc)
+                    .await?;
+            }
         }
+        Ok(())
     }
+    .await;
+    let digest_calc = format!("{}", digest_calc.finalize().to_hex());
 
-    if file_size_calc != file_size {
-        Err(anyhow!(
+    let check = match written {
+        Err(e) => Err(e),
+        Ok(_) if file_size_calc != file_size => Err(anyhow!(
             "{} file size check error, expect: {}, got: {}",
             file_name,
             file_size,
             file_size_calc
-        ))
-    } else {
-        Ok((String::from(file_name), file_size_calc, parts, part_size))
+        )),
+        Ok(_) => match source_digest(&source_file_name).await {
+            Ok(digest) if digest != digest_calc => Err(anyhow!(
+                "{} file hash check error, expect: {}, got: {}",
+                file_name,
+                digest,
+                digest_calc
+            )),
+            result => result.map(|_| ()),
+        },
+    };
+    //the temp file is never left behind in the target tree
+    let replaced = match check {
+        Ok(_) => replace_with_temp(target, &temp_file_name, &target_file_name).await,
+        Err(e) => {
+            drop(target);
+            Err(e)
+        }
+    };
+    if let Err(e) = replaced {
+        let _ = fs::remove_file(&temp_file_name).await;
+        return Err(e);
     }
+    //the copy keeps the mtime and mode of the source
+    task::spawn_blocking(move || {
+        let meta = FileMeta::from_metadata(&std::fs::metadata(&source_file_name)?);
+        restore_metadata(&target_file_name, meta, preserve_owner)
+    })
+    .await??;
+    Ok((String::from(file_name), file_size_calc, parts, part_size))
+}
+
+//blake3 of the source read once more from disk, without holding it in memory
+async fn source_digest(file_name: &str) -> Result<String> {
+    let file_name = String::from(file_name);
+    task::spawn_blocking(move || {
+        let mut digest = Hasher::new();
+        std::io::copy(&mut std::fs::File::open(&file_name)?, &mut digest)?;
+        Ok(format!("{}", digest.finalize().to_hex()))
+    })
+    .await?
 }
 
 pub async fn xcopy_files(config: &Value, source_path: &str, target_path: &str,max_tasks:u64) -> Result<()> {
@@ -93,20 +149,24 @@ pub async fn xcopy_files(config: &Value, source_path: &str, target_path: &str,ma
         };
         Ok(path)
     }
-    use std::collections::HashSet;
-    use std::ffi::OsStr;
     let original_source_path = fine_path(source_path)?;
     let source_path = original_source_path.as_str();
     let target_path = fine_path(target_path)?;
     let target_path = target_path.as_str();
     let client_config = &config["xcopy"];
     let kill_running = client_config["kill_running_exe"].bool(false);
+    let preserve_owner = client_config["preserve_owner"].bool(false);
     let part_size = client_config["part_size"].u64(1024 * 1024);
     let max_tasks = client_config["max_tasks"].u64(max_tasks);
     let meta = fs::metadata(source_path).await?;
+    let mut source_links = Vec::new();
     let (source_file_list, source_path, source_path_is_file) = if meta.is_dir() {
+        let filter = FileFilter::load(client_config, source_path).await?;
+        let follow_links = client_config["follow_links"].bool(false);
+        let entries = get_dir_entries(source_path, &filter, follow_links).await?;
+        source_links = entries.links;
         (
-            get_dir_file_size(source_path).await?,
+            get_files_size(entries.files).await?,
             source_path.to_string(),
             false,
         )
@@ -134,75 +194,89 @@ pub async fn xcopy_files(config: &Value, source_path: &str, target_path: &str,ma
     let file_size = source_file_list.iter().map(|x| x.1).sum::<u64>();
 
     if kill_running {
-        let exe_list = source_file_list
-            .iter()
-            .map(|x| Path::new(&x.0))
-            .filter(|x| x.extension().unwrap_or_else(||OsStr::new("")) == "exe")
-            .map(|x| x.file_name().unwrap().to_str().unwrap())
-            .filter(|x| x.to_lowercase() != "filer.exe")
-            .collect::<HashSet<&str>>();
-        if !exe_list.is_empty() {
-            print!("Kill running exe: ");
-            for image_name in exe_list {
-                print!("{}..", image_name);
-                match kill_running_exe(image_name).await {
-                    Ok(_) => (),
-                    Err(e) => println!("失败: {:?}", e),
-                };
-            }
-        }
-        println!();
+        let files: Vec<&str> = source_file_list.iter().map(|x| x.0.as_str()).collect();
+        stop_running_files(target_path, &files).await;
     }
 
+    //the target root must exist before writes into it are checked to stay inside
+    DirBuilder::new().recursive(true).create(target_path).await?;
     let mut xcopy_error_count: usize = 0;
-    let mut i: usize = 0;
     let mut print_count: usize = 0;
+    //filer binaries are copied aside and swapped in after the run
+    let mut staged: Vec<String> = Vec::new();
 
     if source_path_is_file {
         println!("Copy {} to {}/ ...", original_source_path, target_path);
     } else {
         println!("XCopy files from {}/ to {}/ ...", source_path, target_path);
     }
-    while i < file_count {
-        let mut task_count = 0u64;
-        let mut results: Vec<task::JoinHandle<Result<XCopyReturn>>> =
-            Vec::with_capacity(max_tasks as usize);
-        while task_count < max_tasks && i < file_count {
-            let (file_name, file_size) = source_file_list
-                .get(i)
-                .ok_or_else(||anyhow!("xcopy source_file_list.get() error"))?;
-            let file_size = *file_size;
-            let (task_add, part_size) = calc_parts(file_size, part_size, MAX_SPLIT_PARTS);
-            let file_name: String = file_name.into();
-            let source_path = source_path.to_string();
-            let target_path = target_path.to_string();
-            task_count += task_add;
-            results.push(task::spawn(async move {
-                xcopy_file(&source_path, &target_path, &file_name, file_size, part_size).await
-            }));
-            i += 1;
-        }
-        for result in results {
-            print_count += 1;
-            let result = result.await?;
-            match result {
-                Ok((file_name, file_size, parts, _part_size)) => {
-                    println!(
-                        ">>{: ^#4} {} {}={} pack{} ...",
-                        print_count,
-                        file_name,
-                        file_size,
-                        parts,
-                        if parts > 1 { "s" } else { "" }
-                    );
-                }
-                Err(e) => {
-                    xcopy_error_count += 1;
-                    println!(">>{: ^#4} {:?}", print_count, e);
+    let scheduler = Scheduler::new(max_tasks);
+    let mut running = FuturesUnordered::new();
+    for (file_name, file_size) in &source_file_list {
+        let file_size = *file_size;
+        let (_, part_size) = calc_parts(file_size, part_size, MAX_SPLIT_PARTS);
+        let file_name: String = file_name.into();
+        let source_path = source_path.to_string();
+        let target_path = target_path.to_string();
+        let file_scheduler = scheduler.clone();
+        running.push(scheduler.spawn_file(async move {
+            xcopy_file(
+                &source_path,
+                &target_path,
+                &file_name,
+                file_size,
+                part_size,
+                preserve_owner,
+                &file_scheduler,
+            )
+            .await
+        }));
+    }
+    while let Some(result) = running.next().await {
+        print_count += 1;
+        let result = result?;
+        match result {
+            Ok((file_name, file_size, parts, _part_size)) => {
+                if is_filer_binary(target_path, &file_name) {
+                    staged.push(file_name.clone());
                 }
+                println!(
+                    ">>{: ^#4} {} {}={} pack{} ...",
+                    print_count,
+                    file_name,
+                    file_size,
+                    parts,
+                    if parts > 1 { "s" } else { "" }
+                );
+            }
+            Err(e) => {
+                xcopy_error_count += 1;
+                println!(">>{: ^#4} {:?}", print_count, e);
             }
         }
     }
+    //links are copied as links, not what they point to
+    for (link_name, target) in &source_links {
+        let link_name = &link_name[source_path_len + 1..];
+        match restore_link(target_path, link_name, target) {
+            Ok(_) => println!(">>Link {} -> {}", link_name, target),
+            Err(e) => {
+                xcopy_error_count += 1;
+                println!(">>Link {} fail: {}", link_name, e);
+            }
+        }
+    }
+    let mut restart_binary = None;
+    if xcopy_error_count == 0 && !staged.is_empty() {
+        let mut binaries = Vec::with_capacity(staged.len());
+        for file_name in &staged {
+            let digest = file_digest(&(source_path.clone() + "/" + file_name)).await?;
+            binaries.push((target_file_name(target_path, file_name), digest));
+        }
+        let (error_count, binary) = install_binaries(&binaries).await;
+        xcopy_error_count += error_count;
+        restart_binary = binary;
+    }
 
     println!(
         "Copy {} files with size {} from {}/ to {}/, with failure count {}.",
@@ -217,5 +291,12 @@ pub async fn xcopy_files(config: &Value, source_path: &str, target_path: &str,ma
         max_tasks,
         Byte::from_bytes(part_size as u128).get_appropriate_unit(true)
     );
+    //a non-zero exit status tells scripts the copy is incomplete
+    if xcopy_error_count > 0 {
+        return Err(anyhow!("{} files failed to copy", xcopy_error_count));
+    }
+    if let Some(binary) = restart_binary {
+        restart(&binary)?;
+    }
     Ok(())
 }